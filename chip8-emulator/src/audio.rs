@@ -1,12 +1,148 @@
-use rodio::{OutputStream, Sink};
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::{OutputStream, Sink, Source};
+
+const SAMPLE_RATE: u32 = 48000;
+// tick() is called once per emulated frame, and frames run at 60 Hz (see
+// timing::FRAME).
+const TIMER_HZ: u32 = 60;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+}
+
+impl Waveform {
+    pub fn parse(s: &str) -> Result<Waveform, String> {
+        match s {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            _ => Err(format!("unknown waveform: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AudioConfig {
+    pub frequency: f32,
+    pub waveform: Waveform,
+    pub volume: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> AudioConfig {
+        AudioConfig {
+            frequency: 440.0,
+            waveform: Waveform::Sine,
+            volume: 1.0,
+            attack_ms: 5.0,
+            release_ms: 5.0,
+        }
+    }
+}
+
+// Endless tone whose gain follows the gate with a linear attack/release ramp,
+// so switching the buzzer on and off does not click.
+struct Buzzer {
+    config: AudioConfig,
+    gate: Arc<AtomicBool>,
+    phase: f32,
+    gain: f32,
+}
+
+impl Buzzer {
+    fn new(config: AudioConfig, gate: Arc<AtomicBool>) -> Buzzer {
+        Buzzer {
+            config,
+            gate,
+            phase: 0.0,
+            gain: 0.0,
+        }
+    }
+
+    fn step_for(ms: f32) -> f32 {
+        if ms <= 0.0 {
+            1.0
+        } else {
+            1000.0 / (ms * SAMPLE_RATE as f32)
+        }
+    }
+}
+
+impl Iterator for Buzzer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.gate.load(Ordering::Relaxed) {
+            self.gain = (self.gain + Buzzer::step_for(self.config.attack_ms)).min(1.0);
+        } else {
+            self.gain = (self.gain - Buzzer::step_for(self.config.release_ms)).max(0.0);
+        }
+
+        let wave = match self.config.waveform {
+            Waveform::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (2.0 * PI * self.phase).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+        };
+
+        self.phase = (self.phase + self.config.frequency / SAMPLE_RATE as f32).fract();
+        Some(wave * self.gain * self.config.volume)
+    }
+}
+
+impl Source for Buzzer {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+enum Backend {
+    Device {
+        _sink: Sink,
+        _stream: OutputStream,
+    },
+    Wav {
+        buzzer: Buzzer,
+        writer: hound::WavWriter<BufWriter<File>>,
+    },
+    Null,
+}
 
 pub struct Audio {
-    sink: Sink,
-    _stream: OutputStream,
+    backend: Backend,
+    gate: Arc<AtomicBool>,
 }
 
 impl Audio {
-    pub fn new() -> Result<Audio, String> {
+    pub fn new(config: &AudioConfig) -> Result<Audio, String> {
         let (stream, stream_handle) = match OutputStream::try_default() {
             Ok(v) => v,
             Err(err) => {
@@ -19,20 +155,117 @@ impl Audio {
                 return Err(err.to_string());
             }
         };
-        sink.append(rodio::source::SineWave::new(440.0));
-        sink.pause();
+        let gate = Arc::new(AtomicBool::new(false));
+        sink.append(Buzzer::new(*config, gate.clone()));
         let ret = Audio {
-            sink,
-            _stream: stream,
+            backend: Backend::Device {
+                _sink: sink,
+                _stream: stream,
+            },
+            gate,
         };
         Ok(ret)
     }
 
-    pub fn play(&self) {
-        self.sink.play();
+    // Renders the buzzer into a WAV file instead of playing it, one timer tick
+    // of samples per call to tick().
+    pub fn wav(config: &AudioConfig, path: &str) -> Result<Audio, String> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let writer = match hound::WavWriter::create(path, spec) {
+            Ok(w) => w,
+            Err(err) => {
+                return Err(err.to_string());
+            }
+        };
+        let gate = Arc::new(AtomicBool::new(false));
+        Ok(Audio {
+            backend: Backend::Wav {
+                buzzer: Buzzer::new(*config, gate.clone()),
+                writer,
+            },
+            gate,
+        })
     }
 
-    pub fn pause(&self) {
-        self.sink.pause();
+    pub fn null() -> Audio {
+        Audio {
+            backend: Backend::Null,
+            gate: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn play(&mut self) {
+        self.gate.store(true, Ordering::Relaxed);
+    }
+
+    pub fn pause(&mut self) {
+        self.gate.store(false, Ordering::Relaxed);
+    }
+
+    // Called once per timer tick; only the WAV backend keeps time by it.
+    pub fn tick(&mut self) {
+        if let Backend::Wav { buzzer, writer } = &mut self.backend {
+            for _ in 0..(SAMPLE_RATE / TIMER_HZ) {
+                let sample = buzzer.next().unwrap_or(0.0);
+                if let Err(err) = writer.write_sample((sample * i16::MAX as f32) as i16) {
+                    eprintln!("Could not write audio sample: {}", err);
+                    self.backend = Backend::Null;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: usize = (SAMPLE_RATE / TIMER_HZ) as usize;
+
+    #[test]
+    fn wav_has_a_tick_of_samples_per_tick_with_attack_and_release() {
+        let path = std::env::temp_dir().join(format!("chip8_buzzer_{}.wav", std::process::id()));
+        // A square wave slow enough to stay high for the whole test, so the
+        // samples are just the envelope.
+        let config = AudioConfig {
+            frequency: 1.0,
+            waveform: Waveform::Square,
+            ..AudioConfig::default()
+        };
+        let mut audio = Audio::wav(&config, path.to_str().unwrap()).unwrap();
+        audio.play();
+        audio.tick();
+        // Pausing between ticks, as a reset does, writes nothing by itself.
+        audio.pause();
+        audio.play();
+        audio.tick();
+        audio.pause();
+        audio.tick();
+        drop(audio);
+
+        let samples: Vec<i16> = hound::WavReader::open(&path)
+            .unwrap()
+            .into_samples()
+            .map(Result::unwrap)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(samples.len(), 3 * TICK);
+
+        // 5 ms at 48 kHz is 240 samples up and 240 down.
+        let full = i16::MAX;
+        assert!(samples[0] > 0 && samples[0] < full / 100);
+        assert!((samples[119] - full / 2).abs() < full / 100);
+        assert!(samples[..240].windows(2).all(|w| w[0] <= w[1]));
+        assert!(samples[240..2 * TICK].iter().all(|s| *s == full));
+        assert!(samples[2 * TICK..2 * TICK + 240]
+            .windows(2)
+            .all(|w| w[0] >= w[1]));
+        assert!(samples[2 * TICK + 240..].iter().all(|s| *s == 0));
     }
 }
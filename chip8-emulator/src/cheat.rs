@@ -0,0 +1,358 @@
+use std::fs;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use crate::cpu::RAM_SIZE;
+
+const LIST_LIMIT: usize = 32;
+
+pub const HELP: &str = "Cheat commands:
+  new                      start a new search over all of RAM
+  eq <n>                   keep addresses whose value equals n
+  changed | same           keep addresses that changed / stayed the same since the last search
+  inc | dec                keep addresses that increased / decreased since the last search
+  list                     show the remaining candidates
+  freeze <addr> [n] [name] hold addr at n (default: its current value) every frame
+  unfreeze <addr>          release a frozen address
+  frozen                   show frozen addresses
+  save | load              write / read the cheat list for this ROM";
+
+pub enum Filter {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+pub struct Freeze {
+    pub addr: usize,
+    pub value: u8,
+    pub label: String,
+}
+
+pub struct Cheats {
+    candidates: Option<Vec<usize>>,
+    snapshot: [u8; RAM_SIZE],
+    frozen: Vec<Freeze>,
+    path: PathBuf,
+    console: Receiver<String>,
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(h) => usize::from_str_radix(h, 16).ok(),
+        None => s.parse::<usize>().ok(),
+    }
+}
+
+// Reads console commands on a separate thread so the emulator never blocks on stdin.
+fn spawn_console() -> Receiver<String> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(l) => {
+                    if tx.send(l).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+impl Cheats {
+    // Cheat lists are kept per ROM, in <dir>/<rom hash>.txt.
+    pub fn new(dir: &str, rom_hash: u64) -> Cheats {
+        let mut cheats = Cheats {
+            candidates: None,
+            snapshot: [0; RAM_SIZE],
+            frozen: Vec::new(),
+            path: PathBuf::from(dir).join(format!("{:016x}.txt", rom_hash)),
+            console: spawn_console(),
+        };
+        if cheats.path.exists() {
+            match cheats.load() {
+                Ok(n) => println!("Loaded {} cheats from {}", n, cheats.path.display()),
+                Err(err) => eprintln!("Could not load cheats: {}", err),
+            }
+        }
+        println!("{}", HELP);
+        cheats
+    }
+
+    pub fn start_search(&mut self, ram: &[u8; RAM_SIZE]) -> usize {
+        self.candidates = Some((0..RAM_SIZE).collect());
+        self.snapshot = *ram;
+        RAM_SIZE
+    }
+
+    pub fn search(&mut self, ram: &[u8; RAM_SIZE], filter: Filter) -> Result<usize, String> {
+        let previous = self.snapshot;
+        let candidates = match &mut self.candidates {
+            Some(c) => c,
+            None => {
+                return Err(String::from("no search in progress, use 'new' first"));
+            }
+        };
+        candidates.retain(|a| {
+            let (old, new) = (previous[*a], ram[*a]);
+            match filter {
+                Filter::Equal(n) => new == n,
+                Filter::Changed => new != old,
+                Filter::Unchanged => new == old,
+                Filter::Increased => new > old,
+                Filter::Decreased => new < old,
+            }
+        });
+        self.snapshot = *ram;
+        Ok(candidates.len())
+    }
+
+    pub fn freeze(&mut self, addr: usize, value: u8, label: &str) {
+        self.frozen.retain(|f| f.addr != addr);
+        self.frozen.push(Freeze {
+            addr,
+            value,
+            label: String::from(label),
+        });
+    }
+
+    pub fn unfreeze(&mut self, addr: usize) -> bool {
+        let len = self.frozen.len();
+        self.frozen.retain(|f| f.addr != addr);
+        self.frozen.len() != len
+    }
+
+    pub fn apply(&self, ram: &mut [u8; RAM_SIZE]) {
+        for f in self.frozen.iter() {
+            ram[f.addr] = f.value;
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            if let Err(err) = fs::create_dir_all(dir) {
+                return Err(err.to_string());
+            }
+        }
+        let contents: String = self
+            .frozen
+            .iter()
+            .map(|f| format!("{:03x} {:02x} {}\n", f.addr, f.value, f.label))
+            .collect();
+        match fs::write(&self.path, contents) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    // The file holds one "<addr> <value> [label]" line per cheat, in hex.
+    pub fn load(&mut self) -> Result<usize, String> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(c) => c,
+            Err(err) => {
+                return Err(err.to_string());
+            }
+        };
+        let mut frozen = Vec::new();
+        for (j, line) in contents.lines().enumerate() {
+            let mut parts = line.splitn(3, ' ');
+            let addr = parts.next().and_then(|a| usize::from_str_radix(a, 16).ok());
+            let value = parts.next().and_then(|v| u8::from_str_radix(v, 16).ok());
+            match (addr, value) {
+                (Some(addr), Some(value)) if addr < RAM_SIZE => frozen.push(Freeze {
+                    addr,
+                    value,
+                    label: String::from(parts.next().unwrap_or("").trim()),
+                }),
+                _ if line.trim().is_empty() => (),
+                _ => {
+                    return Err(format!("{}:{}: invalid cheat", self.path.display(), j + 1));
+                }
+            }
+        }
+        self.frozen = frozen;
+        Ok(self.frozen.len())
+    }
+
+    // Runs any commands typed on the console since the last frame.
+    pub fn handle_console(&mut self, ram: &[u8; RAM_SIZE]) {
+        while let Ok(line) = self.console.try_recv() {
+            println!("{}", self.command(&line, ram));
+        }
+    }
+
+    pub fn command(&mut self, line: &str, ram: &[u8; RAM_SIZE]) -> String {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let count = |r: Result<usize, String>| match r {
+            Ok(n) => format!("{} candidates", n),
+            Err(err) => err,
+        };
+        match args.as_slice() {
+            ["new"] => count(Ok(self.start_search(ram))),
+            ["eq", n] => match parse_number(n) {
+                Some(n) if n < 256 => count(self.search(ram, Filter::Equal(n as u8))),
+                _ => format!("invalid value: {}", n),
+            },
+            ["changed"] => count(self.search(ram, Filter::Changed)),
+            ["same"] => count(self.search(ram, Filter::Unchanged)),
+            ["inc"] => count(self.search(ram, Filter::Increased)),
+            ["dec"] => count(self.search(ram, Filter::Decreased)),
+            ["list"] => match &self.candidates {
+                Some(c) => {
+                    let mut out: Vec<String> = c
+                        .iter()
+                        .take(LIST_LIMIT)
+                        .map(|a| format!("{:03x}: {:02x} ({})", a, ram[*a], ram[*a]))
+                        .collect();
+                    if c.len() > LIST_LIMIT {
+                        out.push(format!("... and {} more", c.len() - LIST_LIMIT));
+                    }
+                    out.join("\n")
+                }
+                None => String::from("no search in progress, use 'new' first"),
+            },
+            ["freeze", addr, rest @ ..] => {
+                let addr = match parse_number(addr) {
+                    Some(a) if a < RAM_SIZE => a,
+                    _ => {
+                        return format!("invalid address: {}", addr);
+                    }
+                };
+                let value = match rest.first().map(|v| parse_number(v)) {
+                    Some(Some(v)) if v < 256 => v as u8,
+                    Some(_) => {
+                        return format!("invalid value: {}", rest[0]);
+                    }
+                    None => ram[addr],
+                };
+                let label = if rest.len() > 1 {
+                    rest[1..].join(" ")
+                } else {
+                    String::new()
+                };
+                self.freeze(addr, value, &label);
+                format!("{:03x} frozen at {:02x}", addr, value)
+            }
+            ["unfreeze", addr] => match parse_number(addr) {
+                Some(a) if self.unfreeze(a) => format!("{:03x} released", a),
+                _ => format!("{} is not frozen", addr),
+            },
+            ["frozen"] => {
+                if self.frozen.is_empty() {
+                    return String::from("nothing frozen");
+                }
+                self.frozen
+                    .iter()
+                    .map(|f| format!("{:03x}: {:02x} {}", f.addr, f.value, f.label))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            }
+            ["save"] => match self.save() {
+                Ok(()) => format!("saved to {}", self.path.display()),
+                Err(err) => format!("could not save: {}", err),
+            },
+            ["load"] => match self.load() {
+                Ok(n) => format!("loaded {} cheats", n),
+                Err(err) => format!("could not load: {}", err),
+            },
+            [] => String::new(),
+            _ => String::from(HELP),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheats(dir: &str) -> Cheats {
+        Cheats {
+            candidates: None,
+            snapshot: [0; RAM_SIZE],
+            frozen: Vec::new(),
+            path: PathBuf::from(dir).join("rom.txt"),
+            console: channel().1,
+        }
+    }
+
+    fn candidates(cheats: &Cheats) -> Vec<usize> {
+        cheats.candidates.clone().unwrap()
+    }
+
+    #[test]
+    fn search_needs_a_new_search_first() {
+        let mut cheats = cheats("cheats");
+        assert!(cheats.search(&[0; RAM_SIZE], Filter::Changed).is_err());
+    }
+
+    #[test]
+    fn filters_narrow_down_candidates() {
+        let mut cheats = cheats("cheats");
+        let mut ram = [0; RAM_SIZE];
+        ram[0x300] = 3;
+        ram[0x301] = 3;
+        ram[0x302] = 3;
+        assert_eq!(cheats.start_search(&ram), RAM_SIZE);
+
+        assert_eq!(cheats.search(&ram, Filter::Equal(3)), Ok(3));
+        assert_eq!(candidates(&cheats), vec![0x300, 0x301, 0x302]);
+
+        ram[0x300] = 2;
+        ram[0x301] = 4;
+        assert_eq!(cheats.search(&ram, Filter::Changed), Ok(2));
+        assert_eq!(candidates(&cheats), vec![0x300, 0x301]);
+
+        ram[0x300] = 1;
+        ram[0x301] = 5;
+        assert_eq!(cheats.search(&ram, Filter::Decreased), Ok(1));
+        assert_eq!(candidates(&cheats), vec![0x300]);
+
+        assert_eq!(cheats.search(&ram, Filter::Unchanged), Ok(1));
+        ram[0x300] = 9;
+        assert_eq!(cheats.search(&ram, Filter::Increased), Ok(1));
+        ram[0x300] = 8;
+        assert_eq!(cheats.search(&ram, Filter::Increased), Ok(0));
+    }
+
+    #[test]
+    fn comparisons_are_against_the_previous_search() {
+        let mut cheats = cheats("cheats");
+        let mut ram = [0; RAM_SIZE];
+        cheats.start_search(&ram);
+        ram[0x300] = 1;
+        cheats.search(&ram, Filter::Increased).unwrap();
+        // Still 1 since the last search, so no longer increased.
+        assert_eq!(cheats.search(&ram, Filter::Increased), Ok(0));
+    }
+
+    #[test]
+    fn frozen_values_are_applied_and_saved() {
+        let dir = std::env::temp_dir().join(format!("chip8_cheats_{}", std::process::id()));
+        let mut cheats = cheats(dir.to_str().unwrap());
+        let mut ram = [0; RAM_SIZE];
+        ram[0x2f0] = 7;
+        assert_eq!(
+            cheats.command("freeze 0x2f0 3 infinite lives", &ram),
+            "2f0 frozen at 03"
+        );
+        cheats.command("freeze 0x2f1", &ram);
+        cheats.apply(&mut ram);
+        assert_eq!(ram[0x2f0], 3);
+
+        cheats.save().unwrap();
+        cheats.unfreeze(0x2f0);
+        assert_eq!(cheats.frozen.len(), 1);
+        assert_eq!(cheats.load(), Ok(2));
+        assert_eq!(cheats.frozen[0].label, "infinite lives");
+        assert_eq!(cheats.frozen[1].value, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
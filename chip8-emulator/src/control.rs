@@ -0,0 +1,112 @@
+const FAST_FORWARD_FACTOR: usize = 4;
+const SLOW_MOTION_FACTOR: u32 = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    Pause,
+    FrameAdvance,
+    FastForward,
+    SlowMotion,
+    Reset,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Speed {
+    Normal,
+    FastForward,
+    SlowMotion,
+}
+
+pub struct Control {
+    pub paused: bool,
+    pub speed: Speed,
+    advance: bool,
+    reset: bool,
+}
+
+impl Control {
+    pub fn new() -> Control {
+        Control {
+            paused: false,
+            speed: Speed::Normal,
+            advance: false,
+            reset: false,
+        }
+    }
+
+    // Returns true when the state shown in the window title has changed.
+    pub fn handle_hotkeys(&mut self, hotkeys: &[Hotkey]) -> bool {
+        let mut changed = false;
+        for h in hotkeys {
+            match h {
+                Hotkey::Pause => {
+                    self.paused = !self.paused;
+                    changed = true;
+                }
+                Hotkey::FrameAdvance => {
+                    if self.paused {
+                        self.advance = true;
+                    }
+                }
+                Hotkey::FastForward => {
+                    self.speed = if self.speed == Speed::FastForward {
+                        Speed::Normal
+                    } else {
+                        Speed::FastForward
+                    };
+                    changed = true;
+                }
+                Hotkey::SlowMotion => {
+                    self.speed = if self.speed == Speed::SlowMotion {
+                        Speed::Normal
+                    } else {
+                        Speed::SlowMotion
+                    };
+                    changed = true;
+                }
+                Hotkey::Reset => {
+                    self.reset = true;
+                }
+            }
+        }
+        changed
+    }
+
+    pub fn take_reset(&mut self) -> bool {
+        let ret = self.reset;
+        self.reset = false;
+        ret
+    }
+
+    // Number of frames to emulate before the window is refreshed again.
+    pub fn frames_to_run(&mut self) -> usize {
+        if self.paused {
+            let ret = if self.advance { 1 } else { 0 };
+            self.advance = false;
+            return ret;
+        }
+        match self.speed {
+            Speed::FastForward => FAST_FORWARD_FACTOR,
+            _ => 1,
+        }
+    }
+
+    // Number of 60 Hz periods each emulated frame is stretched over.
+    pub fn frame_periods(&self) -> u32 {
+        match self.speed {
+            Speed::SlowMotion if !self.paused => SLOW_MOTION_FACTOR,
+            _ => 1,
+        }
+    }
+
+    pub fn status(&self) -> String {
+        if self.paused {
+            return String::from("paused");
+        }
+        match self.speed {
+            Speed::Normal => String::from("running"),
+            Speed::FastForward => format!("fast-forward {}x", FAST_FORWARD_FACTOR),
+            Speed::SlowMotion => format!("slow-motion 1/{}x", SLOW_MOTION_FACTOR),
+        }
+    }
+}
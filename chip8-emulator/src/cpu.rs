@@ -1,16 +1,28 @@
+use std::time::Instant;
+
 use minifb::Key;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use crate::audio::Audio;
-use crate::util::{get_bit, get_hex_digits};
+use crate::cheat::Cheats;
+use crate::control::Control;
+use crate::netplay::{Netplay, HASH_INTERVAL};
+use crate::script::{Machine, Script};
+use crate::timing::{vip_cycles, Timing, FRAME, VIP_CYCLES_PER_FRAME};
+use crate::util::{get_bit, get_hex_digits, hash_bytes, HASH_SEED};
 use crate::window::Window;
 
-const RAM_SIZE: usize = 4096;
+pub const RAM_SIZE: usize = 4096;
 const REGISTER_COUNT: usize = 16;
 const STACK_SIZE: usize = 16;
 const RUNLOOP_TIMER_DEFAULT: usize = 8;
+// The original run loop counted down from RUNLOOP_TIMER_DEFAULT to 0 with an
+// instruction on every step, so it ran one more than that per frame.
+const INSTRUCTIONS_PER_FRAME: usize = RUNLOOP_TIMER_DEFAULT + 1;
 const PROGRAM_START: usize = 0x200;
 
-const RAM_DIGITS: [[u8; 5]; 16] = [
+pub const RAM_DIGITS: [[u8; 5]; 16] = [
     [0xf0, 0x90, 0x90, 0x90, 0xf0],
     [0x20, 0x60, 0x20, 0x20, 0x70],
     [0xf0, 0x10, 0xf0, 0x80, 0xf0],
@@ -29,6 +41,40 @@ const RAM_DIGITS: [[u8; 5]; 16] = [
     [0xf0, 0x80, 0xf0, 0x80, 0x80],
 ];
 
+// Behaviour differences between CHIP-8 interpreters, named after Octo's options.
+// The defaults match what this emulator has always done.
+#[derive(Clone, Copy, Debug)]
+pub struct Quirks {
+    pub shift: bool,
+    pub load_store: bool,
+    pub jump: bool,
+    pub logic: bool,
+    pub clip: bool,
+    pub vblank: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store: true,
+            jump: false,
+            logic: false,
+            clip: false,
+            vblank: false,
+        }
+    }
+}
+
+// Stops execution before the matching instruction runs. Opcodes are compared
+// under a mask so that e.g. 1xxx matches every jump.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Breakpoint {
+    Pc(usize),
+    Opcode(u16, u16),
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     ram: [u8; RAM_SIZE],
     v: [u8; REGISTER_COUNT],
@@ -38,6 +84,30 @@ pub struct CPU {
     stack: [usize; STACK_SIZE],
     sp: usize,
     pc: usize,
+    executing: bool,
+    waiting_for_keypress: bool,
+    store_keypress_in: usize,
+    rom: Vec<u8>,
+    quirks: Quirks,
+    cycles_per_frame: usize,
+    timing: Timing,
+    vblank_wait: bool,
+    frame: u32,
+    seed: u64,
+    rng: StdRng,
+    netplay: Option<Netplay>,
+    script: Option<Script>,
+    writes: Vec<usize>,
+    cheats: Option<Cheats>,
+    trace: bool,
+    breakpoints: Vec<Breakpoint>,
+    halted: bool,
+    skip_breakpoint: bool,
+    single_step: bool,
+    in_frame: bool,
+    frame_cycle: usize,
+    keys: [bool; 16],
+    cycles: u64,
     win: Window,
     audio: Audio,
 }
@@ -53,6 +123,30 @@ impl CPU {
             stack: [0; STACK_SIZE],
             sp: 0,
             pc: PROGRAM_START,
+            executing: true,
+            waiting_for_keypress: false,
+            store_keypress_in: 0x0,
+            rom: Vec::new(),
+            quirks: Quirks::default(),
+            cycles_per_frame: INSTRUCTIONS_PER_FRAME,
+            timing: Timing::Fixed,
+            vblank_wait: false,
+            frame: 0,
+            seed: 0,
+            rng: StdRng::from_entropy(),
+            netplay: None,
+            script: None,
+            writes: Vec::new(),
+            cheats: None,
+            trace: false,
+            breakpoints: Vec::new(),
+            halted: false,
+            skip_breakpoint: false,
+            single_step: false,
+            in_frame: false,
+            frame_cycle: 0,
+            keys: [false; 16],
+            cycles: 0,
             win,
             audio,
         };
@@ -60,16 +154,231 @@ impl CPU {
         ret
     }
 
-    pub fn load_rom(&mut self, rom: &Vec<u8>) -> Result<(), &str> {
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), String> {
         if PROGRAM_START + rom.len() >= RAM_SIZE {
-            return Err("Out of memory: program too large");
+            return Err(String::from("Out of memory: program too large"));
         }
-        for (j, c) in rom.into_iter().enumerate() {
+        for (j, c) in rom.iter().enumerate() {
             self.ram[j + PROGRAM_START] = *c;
         }
+        self.rom = rom.to_vec();
         Ok(())
     }
 
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_cycles_per_frame(&mut self, cycles: usize) {
+        self.cycles_per_frame = cycles.max(1);
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    // Makes the CXNN random numbers reproducible; a reset restarts the sequence.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_netplay(&mut self, netplay: Netplay) {
+        self.netplay = Some(netplay);
+    }
+
+    pub fn set_script(&mut self, script: Script) {
+        if let Some(overlay) = script.take_overlay() {
+            self.win.set_overlay(overlay);
+        }
+        self.script = Some(script);
+    }
+
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        self.cheats = Some(cheats);
+    }
+
+    // Prints every executed instruction and key press to stdout.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn add_breakpoint(&mut self, bp: Breakpoint) {
+        if !self.breakpoints.contains(&bp) {
+            self.breakpoints.push(bp);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, bp: Breakpoint) {
+        self.breakpoints.retain(|b| *b != bp);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // Stops before the next instruction, as if it had a breakpoint.
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    // Continues after a breakpoint; the instruction it stopped on runs first.
+    pub fn resume(&mut self) {
+        self.halted = false;
+        self.skip_breakpoint = true;
+    }
+
+    // Resumes for a single instruction and halts again after it.
+    pub fn resume_step(&mut self) {
+        self.resume();
+        self.single_step = true;
+    }
+
+    fn hits_breakpoint(&self, instruction: u16) -> bool {
+        self.breakpoints.iter().any(|bp| match *bp {
+            Breakpoint::Pc(addr) => addr == self.pc,
+            Breakpoint::Opcode(value, mask) => instruction & mask == value,
+        })
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn window(&self) -> &Window {
+        &self.win
+    }
+
+    pub fn refresh(&mut self) {
+        self.win.refresh();
+    }
+
+    pub fn dump_registers(&self) -> String {
+        let v: Vec<String> = self
+            .v
+            .iter()
+            .enumerate()
+            .map(|(j, r)| format!("V{:X}={:02x}", j, r))
+            .collect();
+        let stack: Vec<String> = self.stack[..self.sp]
+            .iter()
+            .map(|s| format!("{:03x}", s))
+            .collect();
+        format!(
+            "PC={:03x} I={:03x} DT={:02x} ST={:02x} SP={:x}\n{}\nstack: [{}]",
+            self.pc,
+            self.i,
+            self.dt,
+            self.st,
+            self.sp,
+            v.join(" "),
+            stack.join(", ")
+        )
+    }
+
+    pub fn machine(&self, keys: &[bool; 16]) -> Machine {
+        Machine {
+            ram: self.ram,
+            v: self.v,
+            i: self.i,
+            pc: self.pc,
+            dt: self.dt,
+            st: self.st,
+            keys: *keys,
+            frame: self.frame,
+        }
+    }
+
+    pub fn load_machine(&mut self, m: &Machine) {
+        self.ram = m.ram;
+        self.v = m.v;
+        self.i = m.i;
+        self.pc = m.pc;
+        self.dt = m.dt;
+        self.st = m.st;
+    }
+
+    // Runs script callbacks on a copy of the machine and takes back whatever
+    // the script changed. Nothing is copied unless a script is loaded and
+    // `hooked` says it has callbacks for this event.
+    fn with_script<H, F>(&mut self, keys: &[bool; 16], hooked: H, f: F) -> Result<(), String>
+    where
+        H: FnOnce(&Script) -> bool,
+        F: FnOnce(&mut Script, &mut Machine) -> Result<(), String>,
+    {
+        let mut script = match self.script.take() {
+            Some(s) if hooked(&s) => s,
+            other => {
+                self.script = other;
+                return Ok(());
+            }
+        };
+        let mut machine = self.machine(keys);
+        let result = f(&mut script, &mut machine);
+        self.load_machine(&machine);
+        if let Some(overlay) = script.take_overlay() {
+            self.win.set_overlay(overlay);
+        }
+        self.script = Some(script);
+        result
+    }
+
+    fn write_ram(&mut self, addr: usize, val: u8) {
+        self.ram[addr] = val;
+        if let Some(script) = &self.script {
+            if script.has_write_hook(addr) {
+                self.writes.push(addr);
+            }
+        }
+    }
+
+    pub fn state_hash(&self) -> u64 {
+        let mut h = hash_bytes(HASH_SEED, &self.ram);
+        h = hash_bytes(h, &self.v);
+        h = hash_bytes(h, &(self.i as u16).to_be_bytes());
+        h = hash_bytes(h, &[self.dt, self.st, self.sp as u8]);
+        h = hash_bytes(h, &(self.pc as u16).to_be_bytes());
+        for s in self.stack.iter() {
+            h = hash_bytes(h, &(*s as u16).to_be_bytes());
+        }
+        let pixels: Vec<u8> = self.win.pixels().iter().map(|p| *p as u8).collect();
+        hash_bytes(h, &pixels)
+    }
+
+    // Soft reset: clears RAM, registers and the display, then reloads the ROM.
+    pub fn reset(&mut self) {
+        self.ram = [0; RAM_SIZE];
+        self.v = [0; REGISTER_COUNT];
+        self.i = 0;
+        self.dt = 0;
+        self.st = 0;
+        self.stack = [0; STACK_SIZE];
+        self.sp = 0;
+        self.pc = PROGRAM_START;
+        self.executing = true;
+        self.waiting_for_keypress = false;
+        self.store_keypress_in = 0x0;
+        self.vblank_wait = false;
+        self.frame = 0;
+        self.in_frame = false;
+        self.frame_cycle = 0;
+        self.halted = false;
+        self.skip_breakpoint = false;
+        self.single_step = false;
+        self.keys = [false; 16];
+        self.rng = StdRng::seed_from_u64(self.seed);
+        self.preload_ram();
+        for (j, c) in self.rom.iter().enumerate() {
+            self.ram[j + PROGRAM_START] = *c;
+        }
+        self.win.clear_screen();
+        self.audio.pause();
+    }
+
     fn preload_ram(&mut self) {
         for (j, d) in RAM_DIGITS.iter().enumerate() {
             for (k, b) in d.iter().enumerate() {
@@ -78,250 +387,398 @@ impl CPU {
         }
     }
 
-    pub fn run_loop(&mut self) -> Result<(), &str> {
-        let mut executing = true;
-        let mut waiting_for_keypress = false;
-        let mut store_keypress_in: usize = 0x0;
-        let mut time_to_runloop: usize = RUNLOOP_TIMER_DEFAULT;
+    pub fn run_loop(&mut self) -> Result<(), String> {
+        let mut control = Control::new();
+        self.win.set_status(&control.status());
+        let mut next_frame = Instant::now();
 
         while self.win.is_open() && !self.win.is_key_down(Key::Escape) && self.pc <= RAM_SIZE {
-            let keys_pressed = self.win.handle_key_events();
-
-            for (j, k) in keys_pressed.iter().enumerate() {
-                if *k {
-                    if waiting_for_keypress {
-                        executing = true;
-                        waiting_for_keypress = false;
-                        self.v[store_keypress_in] = j as u8;
-                        break;
-                    }
+            if control.handle_hotkeys(&self.win.handle_hotkeys()) {
+                self.win.set_status(&control.status());
+                if control.paused {
+                    self.audio.pause();
+                }
+            }
+
+            // Resetting only one side of a netplay session would desync it.
+            if control.take_reset() && self.netplay.is_none() {
+                self.reset();
+            }
+
+            let now = Instant::now();
+            if now >= next_frame {
+                for _ in 0..control.frames_to_run() {
+                    self.run_frame()?;
+                }
+                // After a stall, carry on from now rather than catching up.
+                next_frame = (next_frame + FRAME * control.frame_periods()).max(now);
+            }
+
+            self.win.refresh();
+        }
+        Ok(())
+    }
+
+    // Instructions per frame with fixed timing, machine cycles with VIP timing.
+    fn frame_budget(&self) -> usize {
+        match self.timing {
+            Timing::Fixed => self.cycles_per_frame,
+            Timing::Vip => VIP_CYCLES_PER_FRAME,
+        }
+    }
+
+    // Runs the rest of the current frame, or up to a breakpoint.
+    pub fn run_frame(&mut self) -> Result<(), String> {
+        if !self.in_frame {
+            self.keys = self.read_keys()?;
+            self.frame = self.frame.wrapping_add(1);
+            self.in_frame = true;
+        }
+        let keys_pressed = self.keys;
+
+        let budget = self.frame_budget();
+        while self.frame_cycle < budget {
+            let cost = self.step(&keys_pressed)?;
+            self.frame_cycle += cost;
+            if self.vblank_wait {
+                self.vblank_wait = false;
+                // On the VIP the sprite is drawn after the interrupt, so the
+                // time it takes comes out of the next frame.
+                self.frame_cycle = match self.timing {
+                    Timing::Fixed => budget,
+                    Timing::Vip => budget + cost,
+                };
+                break;
+            }
+            if self.halted {
+                return Ok(());
+            }
+        }
+        // An instruction that runs past the interrupt delays the next frame.
+        self.frame_cycle -= budget;
+        self.in_frame = false;
+
+        if self.dt > 0 {
+            self.dt -= 1;
+        }
+
+        if self.st > 0 {
+            self.audio.play();
+            self.st -= 1;
+        } else if self.st == 0 {
+            self.audio.pause();
+        }
+        self.audio.tick();
+
+        if let Some(cheats) = &mut self.cheats {
+            cheats.handle_console(&self.ram);
+            cheats.apply(&mut self.ram);
+        }
+
+        self.with_script(&keys_pressed, Script::has_frame_hooks, |s, m| {
+            s.frame_end(m)
+        })
+    }
+
+    // The keypad for the next frame: the local keys, anything a script holds
+    // down and, in a netplay session, the other player's keys.
+    fn read_keys(&mut self) -> Result<[bool; 16], String> {
+        let mut keys_pressed = self.win.handle_key_events();
+        if let Some(script) = &self.script {
+            for (k, held) in keys_pressed.iter_mut().zip(script.held_keys().iter()) {
+                *k |= *held;
+            }
+        }
+        if self.netplay.is_some() {
+            let hash = if self.frame.is_multiple_of(HASH_INTERVAL) {
+                self.state_hash()
+            } else {
+                0
+            };
+            if let Some(net) = &mut self.netplay {
+                keys_pressed = net.exchange(self.frame, &keys_pressed, hash)?;
+            }
+        }
+        Ok(keys_pressed)
+    }
+
+    // Runs one instruction and returns how much of the frame budget it used.
+    fn step(&mut self, keys_pressed: &[bool; 16]) -> Result<usize, String> {
+        for (j, k) in keys_pressed.iter().enumerate() {
+            if *k {
+                if self.waiting_for_keypress {
+                    self.executing = true;
+                    self.waiting_for_keypress = false;
+                    self.v[self.store_keypress_in] = j as u8;
+                    break;
+                }
+                if self.trace {
                     println!("{:01x} pressed!", j);
                 }
             }
+        }
 
-            let b1 = self.ram[self.pc] as u16;
-            let b2 = self.ram[self.pc + 1] as u16;
-            let instruction = (b1 * 256) + b2;
+        // The VIP interpreter spins until the next interrupt while FX0A waits.
+        if !self.executing {
+            return Ok(match self.timing {
+                Timing::Fixed => 1,
+                Timing::Vip => self.frame_budget(),
+            });
+        }
 
-            let mut next_instruction = true;
+        let pc = self.pc;
+        self.with_script(keys_pressed, |s| s.has_pc_hook(pc), |s, m| s.pc_reached(m))?;
 
-            if executing {
-                println!(
-                    "{:03x}, {:04x}, {:04x}, {:02x?}",
-                    self.pc, instruction, self.i, self.v
-                );
-                match instruction {
-                    0x00e0 => {
-                        self.win.clear_screen();
-                    }
-                    0x00ee => {
-                        if self.sp == 0 {
-                            return Err("Stack empty, cannot return from subroutine!");
-                        }
-                        self.sp -= 1;
-                        self.pc = self.stack[self.sp];
-                    }
-                    0x1000..=0x1fff => {
-                        self.pc = get_hex_digits(&instruction, 3, 0);
-                        next_instruction = false;
-                    }
-                    0x2000..=0x2fff => {
-                        let loc = get_hex_digits(&instruction, 3, 0);
-                        if self.sp == STACK_SIZE {
-                            return Err("Stack full, cannot push!");
-                        }
-                        self.stack[self.sp] = self.pc;
-                        self.sp += 1;
-                        self.pc = loc;
-                        next_instruction = false;
-                    }
-                    0x3000..=0x3fff => {
-                        let val = get_hex_digits(&instruction, 2, 0);
-                        let reg = get_hex_digits(&instruction, 1, 2);
-                        if self.v[reg] == val as u8 {
-                            self.pc += 2;
-                        }
+        if self.pc + 1 >= RAM_SIZE {
+            return Err(format!("PC out of range: {:03x}", self.pc));
+        }
+        let b1 = self.ram[self.pc] as u16;
+        let b2 = self.ram[self.pc + 1] as u16;
+        let instruction = (b1 * 256) + b2;
+
+        if !self.skip_breakpoint && self.hits_breakpoint(instruction) {
+            self.halted = true;
+            return Ok(0);
+        }
+        self.skip_breakpoint = false;
+        self.cycles += 1;
+        let cost = match self.timing {
+            Timing::Fixed => 1,
+            Timing::Vip => vip_cycles(instruction, &self.v),
+        };
+
+        let mut next_instruction = true;
+
+        if self.trace {
+            println!(
+                "{:03x}, {:04x}, {:04x}, {:02x?}",
+                self.pc, instruction, self.i, self.v
+            );
+        }
+        match instruction {
+            0x00e0 => {
+                self.win.clear_screen();
+            }
+            0x00ee => {
+                if self.sp == 0 {
+                    return Err(String::from("Stack empty, cannot return from subroutine!"));
+                }
+                self.sp -= 1;
+                self.pc = self.stack[self.sp];
+            }
+            0x1000..=0x1fff => {
+                self.pc = get_hex_digits(&instruction, 3, 0);
+                next_instruction = false;
+            }
+            0x2000..=0x2fff => {
+                let loc = get_hex_digits(&instruction, 3, 0);
+                if self.sp == STACK_SIZE {
+                    return Err(String::from("Stack full, cannot push!"));
+                }
+                self.stack[self.sp] = self.pc;
+                self.sp += 1;
+                self.pc = loc;
+                next_instruction = false;
+            }
+            0x3000..=0x3fff => {
+                let val = get_hex_digits(&instruction, 2, 0);
+                let reg = get_hex_digits(&instruction, 1, 2);
+                if self.v[reg] == val as u8 {
+                    self.pc += 2;
+                }
+            }
+            0x4000..=0x4fff => {
+                let val = get_hex_digits(&instruction, 2, 0);
+                let reg = get_hex_digits(&instruction, 1, 2);
+                if self.v[reg] != val as u8 {
+                    self.pc += 2;
+                }
+            }
+            0x5000..=0x5fff => {
+                let reg1 = get_hex_digits(&instruction, 1, 2);
+                let reg2 = get_hex_digits(&instruction, 1, 1);
+                if self.v[reg1] == self.v[reg2] {
+                    self.pc += 2;
+                }
+            }
+            0x6000..=0x6fff => {
+                let val = get_hex_digits(&instruction, 2, 0);
+                let reg = get_hex_digits(&instruction, 1, 2);
+                self.v[reg] = val as u8;
+            }
+            0x7000..=0x7fff => {
+                let val = get_hex_digits(&instruction, 2, 0);
+                let reg = get_hex_digits(&instruction, 1, 2);
+                self.v[reg] = self.v[reg].overflowing_add(val as u8).0;
+            }
+            0x8000..=0x8fff => {
+                let lsb = get_hex_digits(&instruction, 1, 0);
+                let reg1 = get_hex_digits(&instruction, 1, 2);
+                let reg2 = get_hex_digits(&instruction, 1, 1);
+
+                match lsb {
+                    0x0 => {
+                        self.v[reg1] = self.v[reg2];
                     }
-                    0x4000..=0x4fff => {
-                        let val = get_hex_digits(&instruction, 2, 0);
-                        let reg = get_hex_digits(&instruction, 1, 2);
-                        if self.v[reg] != val as u8 {
-                            self.pc += 2;
+                    0x1 => {
+                        self.v[reg1] |= self.v[reg2];
+                        if self.quirks.logic {
+                            self.v[0xf] = 0;
                         }
                     }
-                    0x5000..=0x5fff => {
-                        let reg1 = get_hex_digits(&instruction, 1, 2);
-                        let reg2 = get_hex_digits(&instruction, 1, 1);
-                        if self.v[reg1] == self.v[reg2] {
-                            self.pc += 2;
+                    0x2 => {
+                        self.v[reg1] &= self.v[reg2];
+                        if self.quirks.logic {
+                            self.v[0xf] = 0;
                         }
                     }
-                    0x6000..=0x6fff => {
-                        let val = get_hex_digits(&instruction, 2, 0);
-                        let reg = get_hex_digits(&instruction, 1, 2);
-                        self.v[reg] = val as u8;
-                    }
-                    0x7000..=0x7fff => {
-                        let val = get_hex_digits(&instruction, 2, 0);
-                        let reg = get_hex_digits(&instruction, 1, 2);
-                        self.v[reg] = self.v[reg].overflowing_add(val as u8).0;
-                    }
-                    0x8000..=0x8fff => {
-                        let lsb = get_hex_digits(&instruction, 1, 0);
-                        let reg1 = get_hex_digits(&instruction, 1, 2);
-                        let reg2 = get_hex_digits(&instruction, 1, 1);
-
-                        match lsb {
-                            0x0 => {
-                                self.v[reg1] = self.v[reg2];
-                            }
-                            0x1 => {
-                                self.v[reg1] |= self.v[reg2];
-                            }
-                            0x2 => {
-                                self.v[reg1] &= self.v[reg2];
-                            }
-                            0x3 => {
-                                self.v[reg1] ^= self.v[reg2];
-                            }
-                            0x4 => {
-                                let (res, over) = self.v[reg1].overflowing_add(self.v[reg2]);
-                                self.v[reg1] = res;
-                                self.v[0xf] = if over { 1 } else { 0 };
-                            }
-                            0x5 => {
-                                let (res, over) = self.v[reg1].overflowing_sub(self.v[reg2]);
-                                self.v[reg1] = res;
-                                self.v[0xf] = if over { 0 } else { 1 };
-                            }
-                            0x6 => {
-                                let res = self.v[reg1].overflowing_shr(1).0;
-                                self.v[0xf] = get_bit(&self.v[reg1], 0);
-                                self.v[reg1] = res;
-                            }
-                            0x7 => {
-                                let (res, over) = self.v[reg2].overflowing_sub(self.v[reg1]);
-                                self.v[reg1] = res;
-                                self.v[0xf] = if over { 0 } else { 1 };
-                            }
-                            0xe => {
-                                let res = self.v[reg1].overflowing_shl(1).0;
-                                self.v[0xf] = get_bit(&self.v[reg1], 7);
-                                self.v[reg1] = res;
-                            }
-                            _ => {
-                                println!("Warning: unrecognized instruction: {:04x}", instruction);
-                            }
-                        };
-                    }
-                    0x9000..=0x9fff => {
-                        let reg1 = get_hex_digits(&instruction, 1, 2);
-                        let reg2 = get_hex_digits(&instruction, 1, 1);
-                        if self.v[reg1] != self.v[reg2] {
-                            self.pc += 2;
+                    0x3 => {
+                        self.v[reg1] ^= self.v[reg2];
+                        if self.quirks.logic {
+                            self.v[0xf] = 0;
                         }
                     }
-                    0xa000..=0xafff => {
-                        self.i = get_hex_digits(&instruction, 3, 0);
+                    0x4 => {
+                        let (res, over) = self.v[reg1].overflowing_add(self.v[reg2]);
+                        self.v[reg1] = res;
+                        self.v[0xf] = if over { 1 } else { 0 };
                     }
-                    0xb000..=0xbfff => {
-                        self.pc = get_hex_digits(&instruction, 3, 0) + self.v[0] as usize;
-                        next_instruction = false;
+                    0x5 => {
+                        let (res, over) = self.v[reg1].overflowing_sub(self.v[reg2]);
+                        self.v[reg1] = res;
+                        self.v[0xf] = if over { 0 } else { 1 };
                     }
-                    0xc000..=0xcfff => {
-                        let rnd = rand::random::<u8>();
-                        let val = get_hex_digits(&instruction, 2, 0);
-                        let reg = get_hex_digits(&instruction, 1, 2);
-                        self.v[reg] = rnd & val as u8;
+                    0x6 => {
+                        let src = if self.quirks.shift { reg1 } else { reg2 };
+                        let res = self.v[src].overflowing_shr(1).0;
+                        self.v[0xf] = get_bit(&self.v[src], 0);
+                        self.v[reg1] = res;
                     }
-                    0xd000..=0xdfff => {
-                        let reg1 = get_hex_digits(&instruction, 1, 2);
-                        let reg2 = get_hex_digits(&instruction, 1, 1);
-                        let init_x = self.v[reg1];
-                        let init_y = self.v[reg2];
-                        let mut byte_count = get_hex_digits(&instruction, 1, 0);
-                        let mut bytes_to_print: Vec<u8> = Vec::new();
-                        let mut j = 0;
-                        while byte_count > 0 {
-                            bytes_to_print.push(self.ram[self.i + j]);
-                            byte_count -= 1;
-                            j += 1;
-                        }
-                        self.v[0xf] = self.win.draw(&bytes_to_print, init_x, init_y);
+                    0x7 => {
+                        let (res, over) = self.v[reg2].overflowing_sub(self.v[reg1]);
+                        self.v[reg1] = res;
+                        self.v[0xf] = if over { 0 } else { 1 };
                     }
-                    0xe000..=0xff65 => {
-                        let d1 = get_hex_digits(&instruction, 1, 3);
-                        let d2 = get_hex_digits(&instruction, 1, 2);
-                        let d3 = get_hex_digits(&instruction, 1, 1);
-                        let d4 = get_hex_digits(&instruction, 1, 0);
-
-                        if d1 == 0xe && d3 == 0x9 && d4 == 0xe {
-                            if keys_pressed[self.v[d2] as usize] {
-                                self.pc += 2;
-                            }
-                        } else if d1 == 0xe && d3 == 0xa && d4 == 0x1 {
-                            if !keys_pressed[self.v[d2] as usize] {
-                                self.pc += 2;
-                            }
-                        } else if d1 == 0xf && d3 == 0x0 && d4 == 0x7 {
-                            self.v[d2] = self.dt;
-                        } else if d1 == 0xf && d3 == 0x0 && d4 == 0xa {
-                            executing = false;
-                            waiting_for_keypress = true;
-                            store_keypress_in = d2;
-                        } else if d1 == 0xf && d3 == 0x1 && d4 == 0x5 {
-                            self.dt = self.v[d2];
-                        } else if d1 == 0xf && d3 == 0x1 && d4 == 0x8 {
-                            self.st = self.v[d2];
-                        } else if d1 == 0xf && d3 == 0x1 && d4 == 0xe {
-                            self.i += self.v[d2] as usize;
-                        } else if d1 == 0xf && d3 == 0x2 && d4 == 0x9 {
-                            self.i = (0x10 * self.v[d2]) as usize;
-                        } else if d1 == 0xf && d3 == 0x3 && d4 == 0x3 {
-                            self.ram[self.i] = self.v[d2] / 100;
-                            self.ram[self.i + 1] = (self.v[d2] % 100) / 10;
-                            self.ram[self.i + 2] = self.v[d2] % 10;
-                        } else if d1 == 0xf && d3 == 0x5 && d4 == 0x5 {
-                            for j in 0..=d2 {
-                                self.ram[self.i + j] = self.v[j];
-                            }
-                        } else if d1 == 0xf && d3 == 0x6 && d4 == 0x5 {
-                            for j in 0..=d2 {
-                                self.v[j] = self.ram[self.i + j];
-                            }
-                        } else {
-                            println!("Warning: unrecognized instruction: {:04x}", instruction);
-                        }
+                    0xe => {
+                        let src = if self.quirks.shift { reg1 } else { reg2 };
+                        let res = self.v[src].overflowing_shl(1).0;
+                        self.v[0xf] = get_bit(&self.v[src], 7);
+                        self.v[reg1] = res;
                     }
                     _ => {
                         println!("Warning: unrecognized instruction: {:04x}", instruction);
                     }
                 };
-
-                if next_instruction {
+            }
+            0x9000..=0x9fff => {
+                let reg1 = get_hex_digits(&instruction, 1, 2);
+                let reg2 = get_hex_digits(&instruction, 1, 1);
+                if self.v[reg1] != self.v[reg2] {
                     self.pc += 2;
                 }
             }
-
-            if time_to_runloop == 0 {
-                if self.dt > 0 {
-                    self.dt -= 1;
+            0xa000..=0xafff => {
+                self.i = get_hex_digits(&instruction, 3, 0);
+            }
+            0xb000..=0xbfff => {
+                let reg = if self.quirks.jump {
+                    get_hex_digits(&instruction, 1, 2)
+                } else {
+                    0
+                };
+                self.pc = get_hex_digits(&instruction, 3, 0) + self.v[reg] as usize;
+                next_instruction = false;
+            }
+            0xc000..=0xcfff => {
+                let rnd = self.rng.gen::<u8>();
+                let val = get_hex_digits(&instruction, 2, 0);
+                let reg = get_hex_digits(&instruction, 1, 2);
+                self.v[reg] = rnd & val as u8;
+            }
+            0xd000..=0xdfff => {
+                let reg1 = get_hex_digits(&instruction, 1, 2);
+                let reg2 = get_hex_digits(&instruction, 1, 1);
+                let init_x = self.v[reg1];
+                let init_y = self.v[reg2];
+                let mut byte_count = get_hex_digits(&instruction, 1, 0);
+                let mut bytes_to_print: Vec<u8> = Vec::new();
+                let mut j = 0;
+                while byte_count > 0 {
+                    bytes_to_print.push(self.ram[self.i + j]);
+                    byte_count -= 1;
+                    j += 1;
                 }
+                self.v[0xf] = self
+                    .win
+                    .draw(&bytes_to_print, init_x, init_y, self.quirks.clip);
+                self.vblank_wait = self.quirks.vblank || self.timing == Timing::Vip;
+            }
+            0xe000..=0xff65 => {
+                let d1 = get_hex_digits(&instruction, 1, 3);
+                let d2 = get_hex_digits(&instruction, 1, 2);
+                let d3 = get_hex_digits(&instruction, 1, 1);
+                let d4 = get_hex_digits(&instruction, 1, 0);
 
-                if self.st > 0 {
-                    self.audio.play();
-                    self.st -= 1;
-                } else if self.st == 0 {
-                    self.audio.pause();
+                if d1 == 0xe && d3 == 0x9 && d4 == 0xe {
+                    if keys_pressed[self.v[d2] as usize] {
+                        self.pc += 2;
+                    }
+                } else if d1 == 0xe && d3 == 0xa && d4 == 0x1 {
+                    if !keys_pressed[self.v[d2] as usize] {
+                        self.pc += 2;
+                    }
+                } else if d1 == 0xf && d3 == 0x0 && d4 == 0x7 {
+                    self.v[d2] = self.dt;
+                } else if d1 == 0xf && d3 == 0x0 && d4 == 0xa {
+                    self.executing = false;
+                    self.waiting_for_keypress = true;
+                    self.store_keypress_in = d2;
+                } else if d1 == 0xf && d3 == 0x1 && d4 == 0x5 {
+                    self.dt = self.v[d2];
+                } else if d1 == 0xf && d3 == 0x1 && d4 == 0x8 {
+                    self.st = self.v[d2];
+                } else if d1 == 0xf && d3 == 0x1 && d4 == 0xe {
+                    self.i += self.v[d2] as usize;
+                } else if d1 == 0xf && d3 == 0x2 && d4 == 0x9 {
+                    self.i = (0x10 * self.v[d2]) as usize;
+                } else if d1 == 0xf && d3 == 0x3 && d4 == 0x3 {
+                    self.write_ram(self.i, self.v[d2] / 100);
+                    self.write_ram(self.i + 1, (self.v[d2] % 100) / 10);
+                    self.write_ram(self.i + 2, self.v[d2] % 10);
+                } else if d1 == 0xf && d3 == 0x5 && d4 == 0x5 {
+                    for j in 0..=d2 {
+                        self.write_ram(self.i + j, self.v[j]);
+                    }
+                    if !self.quirks.load_store {
+                        self.i += d2 + 1;
+                    }
+                } else if d1 == 0xf && d3 == 0x6 && d4 == 0x5 {
+                    for j in 0..=d2 {
+                        self.v[j] = self.ram[self.i + j];
+                    }
+                    if !self.quirks.load_store {
+                        self.i += d2 + 1;
+                    }
+                } else {
+                    println!("Warning: unrecognized instruction: {:04x}", instruction);
                 }
+            }
+            _ => {
+                println!("Warning: unrecognized instruction: {:04x}", instruction);
+            }
+        };
 
-                self.win.refresh();
+        if next_instruction {
+            self.pc += 2;
+        }
 
-                time_to_runloop = RUNLOOP_TIMER_DEFAULT;
-            } else {
-                time_to_runloop -= 1;
-            }
+        for addr in std::mem::take(&mut self.writes) {
+            self.with_script(keys_pressed, |_| true, |s, m| s.memory_written(m, addr))?;
         }
-        Ok(())
+        if self.single_step {
+            self.single_step = false;
+            self.halted = true;
+        }
+        Ok(cost)
     }
 }
@@ -0,0 +1,480 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use crate::cpu::{Breakpoint, CPU, RAM_SIZE};
+use crate::script::Machine;
+
+// How long a read waits for the debugger before the emulator gets on with
+// the next frame or window refresh.
+const POLL_TIMEOUT: Duration = Duration::from_millis(1);
+const PACKET_SIZE: usize = 0x1000;
+const PC_REGISTER: usize = 19;
+
+// The order registers appear in in g/G packets and the target description.
+const REGISTERS: [(&str, usize, &str); 20] = [
+    ("v0", 8, "uint8"),
+    ("v1", 8, "uint8"),
+    ("v2", 8, "uint8"),
+    ("v3", 8, "uint8"),
+    ("v4", 8, "uint8"),
+    ("v5", 8, "uint8"),
+    ("v6", 8, "uint8"),
+    ("v7", 8, "uint8"),
+    ("v8", 8, "uint8"),
+    ("v9", 8, "uint8"),
+    ("va", 8, "uint8"),
+    ("vb", 8, "uint8"),
+    ("vc", 8, "uint8"),
+    ("vd", 8, "uint8"),
+    ("ve", 8, "uint8"),
+    ("vf", 8, "uint8"),
+    ("i", 16, "data_ptr"),
+    ("dt", 8, "uint8"),
+    ("st", 8, "uint8"),
+    ("pc", 16, "code_ptr"),
+];
+
+enum Event {
+    Packet(String),
+    Interrupt,
+}
+
+// How the debugging session ended.
+pub enum End {
+    Detached,
+    Killed,
+}
+
+// A GDB Remote Serial Protocol server for one client. The emulator keeps
+// refreshing its window while the debugger has it stopped.
+pub struct Gdb {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    no_ack: bool,
+}
+
+fn io_error(err: std::io::Error) -> String {
+    format!("gdb connection lost: {}", err)
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+fn hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|j| u8::from_str_radix(s.get(j..j + 2)?, 16).ok())
+        .collect()
+}
+
+// Describes every register in full, with its number and offset in g
+// packets, so clients that know nothing about CHIP-8 can still lay them out.
+fn target_xml() -> String {
+    let mut offset = 0;
+    let mut regs = String::new();
+    for (n, (name, bits, kind)) in REGISTERS.iter().enumerate() {
+        let generic = if n == PC_REGISTER {
+            " generic=\"pc\""
+        } else {
+            ""
+        };
+        regs += &format!(
+            "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\" offset=\"{}\" \
+             group=\"general\"{}/>",
+            name, bits, kind, n, offset, generic
+        );
+        offset += bits / 8;
+    }
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.chip8.cpu\">{}</feature></target>",
+        regs
+    )
+}
+
+// Registers are sent little-endian, each as wide as its bitsize.
+fn register_value(m: &Machine, n: usize) -> Option<(usize, usize)> {
+    let value = match n {
+        0..=15 => m.v[n] as usize,
+        16 => m.i,
+        17 => m.dt as usize,
+        18 => m.st as usize,
+        PC_REGISTER => m.pc,
+        _ => {
+            return None;
+        }
+    };
+    Some((value, REGISTERS[n].1 / 8))
+}
+
+fn set_register(m: &mut Machine, n: usize, value: usize) {
+    match n {
+        0..=15 => m.v[n] = value as u8,
+        16 => m.i = value % RAM_SIZE,
+        17 => m.dt = value as u8,
+        18 => m.st = value as u8,
+        PC_REGISTER => m.pc = value % RAM_SIZE,
+        _ => (),
+    }
+}
+
+fn encode_register(value: usize, bytes: usize) -> String {
+    (0..bytes)
+        .map(|j| format!("{:02x}", (value >> (8 * j)) & 0xff))
+        .collect()
+}
+
+fn decode_register(hex: &[u8]) -> usize {
+    hex.iter()
+        .enumerate()
+        .fold(0, |acc, (j, b)| acc | (*b as usize) << (8 * j))
+}
+
+impl Gdb {
+    pub fn listen(port: u16) -> Result<Gdb, String> {
+        let listener = match TcpListener::bind(("127.0.0.1", port)) {
+            Ok(l) => l,
+            Err(err) => {
+                return Err(format!("could not listen on port {}: {}", port, err));
+            }
+        };
+        println!("Waiting for a debugger on localhost:{}...", port);
+        Gdb::accept(&listener)
+    }
+
+    fn accept(listener: &TcpListener) -> Result<Gdb, String> {
+        let stream = match listener.accept() {
+            Ok((s, addr)) => {
+                println!("Debugger attached from {}", addr);
+                s
+            }
+            Err(err) => {
+                return Err(err.to_string());
+            }
+        };
+        if let Err(err) = stream.set_read_timeout(Some(POLL_TIMEOUT)) {
+            return Err(err.to_string());
+        }
+        Ok(Gdb {
+            stream,
+            buffer: Vec::new(),
+            no_ack: false,
+        })
+    }
+
+    // Runs the emulator under the debugger: stopped at first, then running
+    // frames whenever the client continues or steps.
+    pub fn serve(&mut self, cpu: &mut CPU) -> Result<End, String> {
+        cpu.halt();
+        while cpu.window().is_open() {
+            if !cpu.halted() {
+                cpu.run_frame()?;
+                if cpu.halted() {
+                    self.send("S05")?;
+                }
+            }
+            cpu.refresh();
+
+            while let Some(event) = self.poll()? {
+                let packet = match event {
+                    Event::Interrupt => {
+                        if !cpu.halted() {
+                            cpu.halt();
+                            self.send("S02")?;
+                        }
+                        continue;
+                    }
+                    Event::Packet(p) => p,
+                };
+                if let Some(end) = self.handle(cpu, &packet)? {
+                    return Ok(end);
+                }
+            }
+        }
+        Ok(End::Killed)
+    }
+
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> Result<Option<End>, String> {
+        if packet.is_empty() || !packet.is_ascii() {
+            self.send("")?;
+            return Ok(None);
+        }
+        if packet == "QStartNoAckMode" {
+            // The OK itself is still acknowledged, so switch after sending it.
+            self.send("OK")?;
+            self.no_ack = true;
+            return Ok(None);
+        }
+
+        let mut machine = cpu.machine(&[false; 16]);
+        let (cmd, args) = packet.split_at(1);
+        let reply = match cmd {
+            "?" => String::from("S05"),
+            "g" => (0..REGISTERS.len())
+                .filter_map(|n| register_value(&machine, n))
+                .map(|(value, bytes)| encode_register(value, bytes))
+                .collect(),
+            "G" => match hex_bytes(args) {
+                Some(data) if data.len() >= REGISTERS.iter().map(|r| r.1 / 8).sum() => {
+                    let mut offset = 0;
+                    for (n, (_, bits, _)) in REGISTERS.iter().enumerate() {
+                        let end = offset + bits / 8;
+                        set_register(&mut machine, n, decode_register(&data[offset..end]));
+                        offset = end;
+                    }
+                    cpu.load_machine(&machine);
+                    String::from("OK")
+                }
+                _ => String::from("E01"),
+            },
+            "p" => match parse_hex(args).and_then(|n| register_value(&machine, n)) {
+                Some((value, bytes)) => encode_register(value, bytes),
+                None => String::from("E01"),
+            },
+            "P" => {
+                let (n, value) = args.split_once('=').unwrap_or(("", ""));
+                match (parse_hex(n), hex_bytes(value)) {
+                    (Some(n), Some(data)) if n < REGISTERS.len() => {
+                        set_register(&mut machine, n, decode_register(&data));
+                        cpu.load_machine(&machine);
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            "m" => {
+                let (addr, len) = args.split_once(',').unwrap_or(("", ""));
+                match (parse_hex(addr), parse_hex(len)) {
+                    (Some(addr), Some(len)) if addr < RAM_SIZE => machine.ram
+                        [addr..addr.saturating_add(len).min(RAM_SIZE)]
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect(),
+                    _ => String::from("E01"),
+                }
+            }
+            "M" => {
+                let (range, data) = args.split_once(':').unwrap_or(("", ""));
+                let (addr, len) = range.split_once(',').unwrap_or(("", ""));
+                match (parse_hex(addr), parse_hex(len), hex_bytes(data)) {
+                    (Some(addr), Some(len), Some(data))
+                        if len == data.len() && addr.saturating_add(len) <= RAM_SIZE =>
+                    {
+                        machine.ram[addr..addr + len].copy_from_slice(&data);
+                        cpu.load_machine(&machine);
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            // Replies to c and s are sent when the CPU stops again.
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    set_register(&mut machine, PC_REGISTER, addr);
+                    cpu.load_machine(&machine);
+                }
+                if cmd == "s" {
+                    cpu.resume_step();
+                } else {
+                    cpu.resume();
+                }
+                return Ok(None);
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                match (kind, parts.next().and_then(parse_hex)) {
+                    (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                        if cmd == "Z" {
+                            cpu.add_breakpoint(Breakpoint::Pc(addr));
+                        } else {
+                            cpu.remove_breakpoint(Breakpoint::Pc(addr));
+                        }
+                        String::from("OK")
+                    }
+                    _ => String::new(),
+                }
+            }
+            "D" => {
+                self.send("OK")?;
+                cpu.clear_breakpoints();
+                cpu.resume();
+                return Ok(Some(End::Detached));
+            }
+            "k" => {
+                return Ok(Some(End::Killed));
+            }
+            "H" => String::from("OK"),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        self.send(&reply)?;
+        Ok(None)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if packet == "qAttached" {
+            return String::from("1");
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = range.split_once(',').unwrap_or(("", ""));
+            let xml = target_xml();
+            return match (parse_hex(offset), parse_hex(len)) {
+                (Some(offset), Some(len)) if offset <= xml.len() => {
+                    let end = (offset + len).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, &xml[offset..end])
+                }
+                _ => String::from("E01"),
+            };
+        }
+        String::new()
+    }
+
+    fn send(&mut self, data: &str) -> Result<(), String> {
+        let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        self.stream.write_all(packet.as_bytes()).map_err(io_error)
+    }
+
+    // Returns the next complete packet or interrupt from the client, if one
+    // has arrived.
+    fn poll(&mut self) -> Result<Option<Event>, String> {
+        let mut chunk = [0; PACKET_SIZE];
+        match self.stream.read(&mut chunk) {
+            Ok(0) => {
+                return Err(String::from("gdb connection closed"));
+            }
+            Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+            Err(err)
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {}
+            Err(err) => {
+                return Err(io_error(err));
+            }
+        }
+
+        loop {
+            match self.buffer.first() {
+                None => {
+                    return Ok(None);
+                }
+                Some(b'$') => break,
+                Some(0x03) => {
+                    self.buffer.remove(0);
+                    return Ok(Some(Event::Interrupt));
+                }
+                // Acks from the client and line noise.
+                Some(_) => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+
+        let end = match self.buffer.iter().position(|b| *b == b'#') {
+            Some(e) if self.buffer.len() >= e + 3 => e,
+            _ => {
+                return Ok(None);
+            }
+        };
+        let packet: Vec<u8> = self.buffer.drain(..end + 3).collect();
+        let data = String::from_utf8_lossy(&packet[1..end]).into_owned();
+        let checksum = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+
+        if !self.no_ack {
+            let valid = checksum == Some(data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b)));
+            let ack: &[u8] = if valid { b"+" } else { b"-" };
+            self.stream.write_all(ack).map_err(io_error)?;
+            if !valid {
+                return Ok(None);
+            }
+        }
+        Ok(Some(Event::Packet(data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Audio;
+    use crate::window::Window;
+    use std::thread;
+
+    // Sends a packet and returns the reply, skipping the server's acks.
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        stream
+            .write_all(format!("${}#{:02x}", data, checksum).as_bytes())
+            .unwrap();
+        let mut reply = Vec::new();
+        let mut byte = [0; 1];
+        while reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+            stream.read_exact(&mut byte).unwrap();
+            if !reply.is_empty() || byte[0] == b'$' {
+                reply.push(byte[0]);
+            }
+        }
+        String::from_utf8(reply[1..reply.len() - 3].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn target_description_numbers_every_register() {
+        let xml = target_xml();
+        assert_eq!(xml.matches("<reg ").count(), REGISTERS.len());
+        assert!(
+            xml.contains("name=\"i\" bitsize=\"16\" type=\"data_ptr\" regnum=\"16\" offset=\"16\"")
+        );
+        assert!(xml.contains("regnum=\"19\" offset=\"20\" group=\"general\" generic=\"pc\""));
+    }
+
+    #[test]
+    fn client_can_inspect_break_and_continue() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let replies = vec![
+                request(&mut stream, "?"),
+                request(&mut stream, "g"),
+                request(&mut stream, "m200,4"),
+                request(&mut stream, "Z0,204"),
+                request(&mut stream, "c"),
+                request(&mut stream, "g"),
+            ];
+            stream.write_all(b"$k#6b").unwrap();
+            replies
+        });
+
+        let mut cpu = CPU::new(Window::headless(), Audio::null());
+        // v0 := 5, then loop { v0 += 1 }
+        cpu.load_rom(&[0x60, 0x05, 0x70, 0x01, 0x12, 0x02]).unwrap();
+        let mut gdb = Gdb::accept(&listener).unwrap();
+        assert!(matches!(gdb.serve(&mut cpu), Ok(End::Killed)));
+
+        let replies = client.join().unwrap();
+        let registers = |pc: &str, v0: &str| format!("{}{}00000000{}", v0, "00".repeat(15), pc);
+        assert_eq!(
+            replies,
+            vec![
+                String::from("S05"),
+                registers("0002", "00"),
+                String::from("60057001"),
+                String::from("OK"),
+                String::from("S05"),
+                registers("0402", "06"),
+            ]
+        );
+    }
+}
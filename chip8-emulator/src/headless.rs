@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::Instant;
+
+use crate::cpu::CPU;
+use crate::window::{HEIGHT, WIDTH};
+
+const PNG_SCALE: usize = 8;
+
+// Why a headless run stopped; anything but a crash is a normal exit.
+pub enum Stop {
+    Frames,
+    Breakpoint,
+}
+
+// Runs up to `frames` frames as fast as possible, or until a breakpoint is hit.
+pub fn run(cpu: &mut CPU, frames: u64) -> Result<Stop, String> {
+    let start = Instant::now();
+    let mut stop = Stop::Frames;
+    for _ in 0..frames {
+        cpu.run_frame()?;
+        if cpu.halted() {
+            stop = Stop::Breakpoint;
+            break;
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let ips = if elapsed > 0.0 {
+        cpu.cycles() as f64 / elapsed
+    } else {
+        0.0
+    };
+    println!(
+        "{} instructions in {:.3}s ({:.0} instructions/s)",
+        cpu.cycles(),
+        elapsed,
+        ips
+    );
+    Ok(stop)
+}
+
+pub fn ascii(cpu: &CPU) -> String {
+    let pixels = cpu.window().pixels();
+    pixels
+        .chunks(WIDTH)
+        .map(|row| {
+            let mut line: String = row.iter().map(|p| if *p { '#' } else { '.' }).collect();
+            line.push('\n');
+            line
+        })
+        .collect()
+}
+
+// Writes the display as text, or as a PNG scaled up like the window when the
+// file name ends in .png.
+pub fn save(cpu: &CPU, path: &str) -> Result<(), String> {
+    if !path.to_lowercase().ends_with(".png") {
+        return match std::fs::write(path, ascii(cpu)) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        };
+    }
+
+    let file = match File::create(path) {
+        Ok(f) => f,
+        Err(err) => {
+            return Err(err.to_string());
+        }
+    };
+    let (w, h) = (WIDTH * PNG_SCALE, HEIGHT * PNG_SCALE);
+    let mut encoder = png::Encoder::new(BufWriter::new(file), w as u32, h as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let framebuffer = cpu.window().framebuffer();
+    let mut data = Vec::with_capacity(w * h * 3);
+    for y in 0..h {
+        for x in 0..w {
+            let p = framebuffer[(y / PNG_SCALE) * WIDTH + x / PNG_SCALE];
+            data.extend_from_slice(&p.to_be_bytes()[1..]);
+        }
+    }
+
+    let result = encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data));
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
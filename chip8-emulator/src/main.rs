@@ -1,11 +1,14 @@
+extern crate hound;
 extern crate minifb;
+extern crate png;
 extern crate rand;
+extern crate rhai;
 extern crate rodio;
 
-use std::{env, fs};
+use std::env;
 
 mod cpu;
-use cpu::CPU;
+use cpu::{Breakpoint, CPU};
 
 mod audio;
 use audio::Audio;
@@ -15,50 +18,212 @@ use window::Window;
 
 mod util;
 
+mod control;
+
+mod options;
+
+mod octo;
+
+mod rom;
+
+mod netplay;
+use netplay::Netplay;
+
+mod script;
+use script::Script;
+
+mod cheat;
+use cheat::Cheats;
+
+mod headless;
+use headless::Stop;
+
+mod timing;
+use timing::Timing;
+
+mod gdb;
+use gdb::{End, Gdb};
+
 fn main() {
     println!("chip8-rust: CHIP-8 emulator written in Rust");
 
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        return eprintln!("Usage: {} <rom-file-name>", args[0]);
-    }
+    let options = match options::parse(&args) {
+        Ok(o) => o,
+        Err(err) => {
+            return eprintln!("{}\nUsage: {} {}", err, args[0], options::USAGE);
+        }
+    };
 
-    let filename = String::from(&args[1]);
+    let filename = options.rom.clone();
 
-    let rom = match fs::read(&filename) {
+    let rom = match rom::load(&filename) {
         Err(why) => {
-            return eprintln!("Could not open file: {}", why.to_string());
+            return eprintln!("Could not open file: {}", why);
         }
-        Ok(file) => file,
+        Ok(rom) => rom,
     };
 
-    let audio = match Audio::new() {
-        Ok(a) => a,
-        Err(err) => {
-            return eprintln!("Could not initialize audio device: {}", err);
+    let audio = if let Some(path) = &options.wav {
+        match Audio::wav(&options.audio, path) {
+            Ok(a) => a,
+            Err(err) => {
+                return eprintln!("Could not create WAV file: {}", err);
+            }
+        }
+    } else if options.no_audio || options.headless {
+        Audio::null()
+    } else {
+        match Audio::new(&options.audio) {
+            Ok(a) => a,
+            Err(err) => {
+                eprintln!("Could not initialize audio device, sound disabled: {}", err);
+                Audio::null()
+            }
         }
     };
 
-    let win = match Window::new(&format!("chip8-rust: {}", filename)) {
-        Ok(win) => win,
-        Err(err) => {
-            return eprintln!("Could not initialize window: {}", &err.to_string());
+    let mut win = if options.headless {
+        Window::headless()
+    } else {
+        match Window::new(&format!("chip8-rust: {}", filename)) {
+            Ok(win) => win,
+            Err(err) => {
+                return eprintln!("Could not initialize window: {}", err);
+            }
         }
     };
 
+    if let Some((px_on, px_off)) = rom.options.palette {
+        win.set_palette(px_on, px_off);
+    }
+
     let mut cpu = CPU::new(win, audio);
-    match cpu.load_rom(&rom) {
+    if let Some(quirks) = rom.options.quirks {
+        cpu.set_quirks(quirks);
+    }
+    if let Some(tickrate) = rom.options.tickrate {
+        cpu.set_cycles_per_frame(tickrate);
+    }
+    if options.vip {
+        cpu.set_timing(Timing::Vip);
+    }
+    match cpu.load_rom(&rom.data) {
         Ok(()) => (),
         Err(err) => {
             return eprintln!("Could not initialize CPU: {}", err);
         }
     };
 
+    let rom_hash = util::hash_bytes(util::HASH_SEED, &rom.data);
+    let seed = options.seed.unwrap_or_else(rand::random);
+    let netplay = if let Some(addr) = &options.host {
+        Some(Netplay::host(addr, seed, rom_hash))
+    } else {
+        options
+            .join
+            .as_ref()
+            .map(|addr| Netplay::join(addr, rom_hash))
+    };
+    match netplay {
+        Some(Ok(net)) => {
+            cpu.set_seed(net.seed);
+            cpu.set_netplay(net);
+        }
+        Some(Err(err)) => {
+            return eprintln!("Could not start netplay: {}", err);
+        }
+        None => cpu.set_seed(seed),
+    }
+
+    cpu.set_trace(options.trace);
+    if options.cheats {
+        cpu.set_cheats(Cheats::new(&options.cheats_dir, rom_hash));
+    }
+
+    if let Some(path) = &options.script {
+        match Script::load(path, cpu.machine(&[false; 16])) {
+            Ok(script) => cpu.set_script(script),
+            Err(err) => {
+                return eprintln!("Could not load script: {}", err);
+            }
+        }
+    }
+
+    if options.headless {
+        let status = run_headless(&mut cpu, &options);
+        // process::exit skips destructors, and dropping the CPU is what
+        // finishes a --wav file.
+        drop(cpu);
+        std::process::exit(status);
+    }
+
+    if let Some(port) = options.gdb {
+        let result = match Gdb::listen(port) {
+            Ok(mut gdb) => gdb.serve(&mut cpu),
+            Err(err) => {
+                return eprintln!("Could not start debugger: {}", err);
+            }
+        };
+        match result {
+            Ok(End::Detached) => println!("Debugger detached"),
+            Ok(End::Killed) => {
+                return;
+            }
+            Err(err) => {
+                return eprintln!("CPU crashed: {}", err);
+            }
+        }
+    }
+
     match cpu.run_loop() {
         Ok(()) => (),
         Err(err) => {
-            return eprintln!("CPU crashed: {}", err);
+            eprintln!("CPU crashed: {}", err);
+        }
+    }
+}
+
+// Exits with status 1 if the ROM crashed and 2 if a --until-* condition was
+// given but never reached, so build pipelines can tell the cases apart.
+fn run_headless(cpu: &mut CPU, options: &options::Options) -> i32 {
+    if let Some(pc) = options.until_pc {
+        cpu.add_breakpoint(Breakpoint::Pc(pc));
+    }
+    if let Some((opcode, mask)) = options.until_opcode {
+        cpu.add_breakpoint(Breakpoint::Opcode(opcode, mask));
+    }
+
+    let result = headless::run(cpu, options.frames);
+    let status = match &result {
+        Ok(Stop::Breakpoint) => {
+            println!("Stopped at breakpoint");
+            0
+        }
+        Ok(Stop::Frames) => {
+            println!("Stopped after {} frames", options.frames);
+            if options.until_pc.is_some() || options.until_opcode.is_some() {
+                2
+            } else {
+                0
+            }
+        }
+        Err(err) => {
+            println!("CPU crashed: {}", err);
+            1
+        }
+    };
+
+    println!("{}", cpu.dump_registers());
+    match &options.out {
+        Some(path) => {
+            if let Err(err) = headless::save(cpu, path) {
+                eprintln!("Could not save display: {}", err);
+                return 1;
+            }
         }
+        None => print!("{}", headless::ascii(cpu)),
     }
+    status
 }
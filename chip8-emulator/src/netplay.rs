@@ -0,0 +1,304 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+const MAGIC: &[u8; 4] = b"C8NP";
+const VERSION: u8 = 1;
+// Both sides exchange a state hash every this many frames, once a second.
+pub const HASH_INTERVAL: u32 = 60;
+// How long to wait on the peer before giving up on the session.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Two-player lockstep: every frame each side sends its local keypad and waits
+// for the peer's, and both run the frame with the union of the two.
+pub struct Netplay {
+    stream: TcpStream,
+    pub seed: u64,
+}
+
+fn keys_to_bits(keys: &[bool; 16]) -> u16 {
+    keys.iter()
+        .enumerate()
+        .fold(0, |acc, (j, k)| if *k { acc | (1 << j) } else { acc })
+}
+
+fn io_error(err: std::io::Error) -> String {
+    match err.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => format!(
+            "netplay peer stopped responding for {} seconds (disconnected, paused or out of step)",
+            TIMEOUT.as_secs()
+        ),
+        std::io::ErrorKind::UnexpectedEof => String::from("netplay peer disconnected"),
+        _ => format!("netplay connection lost: {}", err),
+    }
+}
+
+// Listens on `addr`, e.g. 127.0.0.1:7000, for a player to join.
+pub fn listen(addr: &str) -> Result<TcpListener, String> {
+    TcpListener::bind(addr).map_err(|err| format!("could not listen on {}: {}", addr, err))
+}
+
+impl Netplay {
+    pub fn host(addr: &str, seed: u64, rom_hash: u64) -> Result<Netplay, String> {
+        let listener = listen(addr)?;
+        println!("Waiting for a player to join on {}...", addr);
+        Netplay::accept(&listener, seed, rom_hash)
+    }
+
+    pub fn accept(listener: &TcpListener, seed: u64, rom_hash: u64) -> Result<Netplay, String> {
+        let stream = match listener.accept() {
+            Ok((s, addr)) => {
+                println!("Player joined from {}", addr);
+                s
+            }
+            Err(err) => {
+                return Err(err.to_string());
+            }
+        };
+
+        let mut hello = Vec::with_capacity(21);
+        hello.extend_from_slice(MAGIC);
+        hello.push(VERSION);
+        hello.extend_from_slice(&seed.to_be_bytes());
+        hello.extend_from_slice(&rom_hash.to_be_bytes());
+
+        let mut net = Netplay { stream, seed };
+        net.setup()?;
+        net.send(&hello)?;
+
+        let mut reply = [0; 13];
+        net.recv(&mut reply)?;
+        if &reply[0..4] != MAGIC || reply[4] != VERSION {
+            return Err(String::from("peer is not a compatible chip8-rust instance"));
+        }
+        if u64::from_be_bytes(reply[5..13].try_into().unwrap()) != rom_hash {
+            return Err(String::from("peer is running a different ROM"));
+        }
+        Ok(net)
+    }
+
+    pub fn join(addr: &str, rom_hash: u64) -> Result<Netplay, String> {
+        let stream = match TcpStream::connect(addr) {
+            Ok(s) => s,
+            Err(err) => {
+                return Err(format!("could not connect to {}: {}", addr, err));
+            }
+        };
+
+        let mut net = Netplay { stream, seed: 0 };
+        net.setup()?;
+
+        let mut hello = [0; 21];
+        net.recv(&mut hello)?;
+        if &hello[0..4] != MAGIC || hello[4] != VERSION {
+            return Err(String::from("host is not a compatible chip8-rust instance"));
+        }
+        net.seed = u64::from_be_bytes(hello[5..13].try_into().unwrap());
+
+        let mut reply = Vec::with_capacity(13);
+        reply.extend_from_slice(MAGIC);
+        reply.push(VERSION);
+        reply.extend_from_slice(&rom_hash.to_be_bytes());
+        net.send(&reply)?;
+
+        if u64::from_be_bytes(hello[13..21].try_into().unwrap()) != rom_hash {
+            return Err(String::from("host is running a different ROM"));
+        }
+        println!("Joined game at {}", addr);
+        Ok(net)
+    }
+
+    fn setup(&mut self) -> Result<(), String> {
+        self.stream.set_nodelay(true).map_err(io_error)?;
+        self.stream
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(io_error)?;
+        self.stream
+            .set_write_timeout(Some(TIMEOUT))
+            .map_err(io_error)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> Result<(), String> {
+        self.stream.write_all(buf).map_err(io_error)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        self.stream.read_exact(buf).map_err(io_error)
+    }
+
+    // Trades this frame's keypad with the peer and returns the combined
+    // keypad. `hash` is the machine state hash on frames that are checked
+    // (every HASH_INTERVAL) and 0 otherwise.
+    pub fn exchange(
+        &mut self,
+        frame: u32,
+        keys: &[bool; 16],
+        hash: u64,
+    ) -> Result<[bool; 16], String> {
+        let mut msg = Vec::with_capacity(14);
+        msg.extend_from_slice(&frame.to_be_bytes());
+        msg.extend_from_slice(&keys_to_bits(keys).to_be_bytes());
+        msg.extend_from_slice(&hash.to_be_bytes());
+        self.send(&msg)?;
+
+        let mut peer = [0; 14];
+        self.recv(&mut peer)?;
+        let peer_frame = u32::from_be_bytes(peer[0..4].try_into().unwrap());
+        let peer_keys = u16::from_be_bytes(peer[4..6].try_into().unwrap());
+        let peer_hash = u64::from_be_bytes(peer[6..14].try_into().unwrap());
+
+        if peer_frame != frame {
+            return Err(format!(
+                "netplay out of step: local frame {}, peer frame {}",
+                frame, peer_frame
+            ));
+        }
+        if peer_hash != hash {
+            return Err(format!(
+                "netplay desync at frame {}: state hash {:016x}, peer {:016x}",
+                frame, hash, peer_hash
+            ));
+        }
+
+        let mut combined = *keys;
+        for (j, k) in combined.iter_mut().enumerate() {
+            *k |= peer_keys & (1 << j) != 0;
+        }
+        Ok(combined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Audio;
+    use crate::cpu::CPU;
+    use crate::octo;
+    use crate::script::Script;
+    use crate::window::Window;
+    use std::thread;
+
+    const FRAMES: u32 = 300;
+
+    // Each side presses a different key pattern and hashes its frame number
+    // the same way, as two emulators in step would.
+    fn play(mut net: Netplay, player: usize) -> Vec<[bool; 16]> {
+        (1..=FRAMES)
+            .map(|frame| {
+                let mut keys = [false; 16];
+                keys[(frame as usize + player * 5) % 16] = true;
+                let hash = if frame.is_multiple_of(HASH_INTERVAL) {
+                    u64::from(frame) * 0x9e37_79b9
+                } else {
+                    0
+                };
+                net.exchange(frame, &keys, hash).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn host_and_client_stay_in_step_over_loopback() {
+        let listener = listen("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let host = thread::spawn(move || {
+            let net = Netplay::accept(&listener, 42, 7).unwrap();
+            play(net, 0)
+        });
+
+        let client = Netplay::join(&addr, 7).unwrap();
+        assert_eq!(client.seed, 42);
+        let joined = play(client, 1);
+        let hosted = host.join().unwrap();
+
+        assert_eq!(hosted, joined);
+        assert_eq!(joined[0].iter().filter(|k| **k).count(), 2);
+    }
+
+    #[test]
+    fn state_hash_mismatch_is_reported() {
+        let listener = listen("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let host = thread::spawn(move || {
+            let mut net = Netplay::accept(&listener, 0, 7).unwrap();
+            net.exchange(HASH_INTERVAL, &[false; 16], 1)
+        });
+
+        let mut client = Netplay::join(&addr, 7).unwrap();
+        let err = client.exchange(HASH_INTERVAL, &[false; 16], 2).unwrap_err();
+        assert!(err.contains("desync"), "{}", err);
+        assert!(host.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let listener = listen("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let host = thread::spawn(move || {
+            let net = Netplay::accept(&listener, 0, 7).unwrap();
+            // Hold the connection open without ever answering.
+            thread::sleep(TIMEOUT + Duration::from_secs(1));
+            drop(net);
+        });
+
+        let mut client = Netplay::join(&addr, 7).unwrap();
+        let err = client.exchange(1, &[false; 16], 0).unwrap_err();
+        assert!(err.contains("stopped responding"), "{}", err);
+        host.join().unwrap();
+    }
+
+    // Mixes random numbers and both players' keys into the machine state.
+    const GAME: &str = ": main
+          v1 := 1
+          v2 := 2
+          loop
+            v0 := random 0xff
+            v3 += v0
+            if v1 key then v4 += 1
+            if v2 key then v5 += 1
+            i := buf
+            save v5
+          again
+        : buf 0 0 0 0 0 0";
+
+    // Runs the game for FRAMES frames with a script pressing this player's
+    // key, and returns the final state hash and the key counters.
+    fn run_cpu(net: Netplay, keys: &str) -> Result<(u64, u8, u8), String> {
+        let mut cpu = CPU::new(Window::headless(), Audio::null());
+        cpu.load_rom(&octo::assemble(GAME).unwrap())?;
+        cpu.set_seed(net.seed);
+        cpu.set_netplay(net);
+        let script = Script::compile("keys", keys, cpu.machine(&[false; 16]))?;
+        cpu.set_script(script);
+        for _ in 0..FRAMES {
+            cpu.run_frame()?;
+        }
+        let machine = cpu.machine(&[false; 16]);
+        Ok((cpu.state_hash(), machine.v[4], machine.v[5]))
+    }
+
+    #[test]
+    fn two_cpus_stay_in_lockstep() {
+        let listener = listen("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let host = thread::spawn(move || {
+            let net = Netplay::accept(&listener, 1234, 7).unwrap();
+            run_cpu(
+                net,
+                "on_frame(|| if frame() % 2 == 0 { press(1) } else { release(1) });",
+            )
+        });
+
+        let client = Netplay::join(&addr, 7).unwrap();
+        let joined = run_cpu(
+            client,
+            "on_frame(|| if frame() % 3 == 0 { press(2) } else { release(2) });",
+        )
+        .unwrap();
+        let hosted = host.join().unwrap().unwrap();
+
+        assert_eq!(hosted, joined);
+        // Both players' keys reached both machines.
+        assert!(joined.1 > 0 && joined.2 > 0);
+    }
+}
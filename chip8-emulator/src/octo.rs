@@ -0,0 +1,866 @@
+// A compact assembler for Octo source, which is what Octo cartridges carry.
+// It covers the CHIP-8 subset of the language plus :const, :alias, :macro,
+// :calc, :unpack and :org; SCHIP and XO-CHIP statements are rejected.
+use std::collections::HashMap;
+
+const PROGRAM_START: usize = 0x200;
+const RAM_SIZE: usize = 4096;
+const MAX_MACRO_EXPANSIONS: usize = 10000;
+
+const UNSUPPORTED: [&str; 17] = [
+    "hires",
+    "lores",
+    "scroll-down",
+    "scroll-up",
+    "scroll-left",
+    "scroll-right",
+    "exit",
+    "bighex",
+    "saveflags",
+    "loadflags",
+    "plane",
+    "audio",
+    "pitch",
+    "long",
+    ":next",
+    ":stringmode",
+    ":pointer",
+];
+
+#[derive(Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+enum Fixup {
+    Address,
+    Unpack,
+}
+
+enum Operand {
+    Register(usize),
+    Value(u8),
+}
+
+enum Condition {
+    Equal(usize, Operand),
+    NotEqual(usize, Operand),
+    Less(usize, Operand),
+    Greater(usize, Operand),
+    LessEqual(usize, Operand),
+    GreaterEqual(usize, Operand),
+    Key(usize),
+    NotKey(usize),
+}
+
+struct Assembler {
+    tokens: Vec<Token>,
+    pos: usize,
+    line: usize,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, usize>,
+    consts: HashMap<String, f64>,
+    aliases: HashMap<String, usize>,
+    macros: HashMap<String, (Vec<String>, Vec<Token>)>,
+    fixups: Vec<(String, usize, Fixup)>,
+    loops: Vec<(usize, Vec<usize>)>,
+    branches: Vec<usize>,
+    expansions: usize,
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut asm = Assembler::new(source);
+    match asm.run() {
+        Ok(()) => Ok(asm.rom),
+        Err(err) => Err(format!("line {}: {}", asm.line, err)),
+    }
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (j, line) in source.lines().enumerate() {
+        let code = match line.find('#') {
+            Some(c) => &line[..c],
+            None => line,
+        };
+        for t in code.split_whitespace() {
+            tokens.push(Token {
+                text: String::from(t),
+                line: j + 1,
+            });
+        }
+    }
+    tokens
+}
+
+fn parse_number(s: &str) -> Option<f64> {
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(d) => (true, d),
+        None => (false, s),
+    };
+    let n = if let Some(h) = digits.strip_prefix("0x") {
+        i64::from_str_radix(h, 16).ok()? as f64
+    } else if let Some(b) = digits.strip_prefix("0b") {
+        i64::from_str_radix(b, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<f64>().ok()?
+    } else {
+        return None;
+    };
+    Some(if neg { -n } else { n })
+}
+
+impl Assembler {
+    fn new(source: &str) -> Assembler {
+        Assembler {
+            tokens: tokenize(source),
+            pos: 0,
+            line: 0,
+            rom: Vec::new(),
+            here: PROGRAM_START,
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            loops: Vec::new(),
+            branches: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        // Execution starts at 0x200, so reserve it for a jump to main.
+        self.emit(0x1000)?;
+        self.fixups
+            .push((String::from("main"), PROGRAM_START, Fixup::Address));
+
+        while self.pos < self.tokens.len() {
+            self.statement()?;
+        }
+
+        if !self.loops.is_empty() {
+            return Err(String::from("'loop' without matching 'again'"));
+        }
+        if !self.branches.is_empty() {
+            return Err(String::from("'begin' without matching 'end'"));
+        }
+
+        let fixups = std::mem::take(&mut self.fixups);
+        for (name, addr, kind) in fixups {
+            let target = match self.labels.get(&name) {
+                Some(t) => *t,
+                None => {
+                    return Err(format!("undefined label: {}", name));
+                }
+            };
+            let offset = addr - PROGRAM_START;
+            match kind {
+                Fixup::Address => {
+                    self.rom[offset] |= ((target >> 8) & 0xf) as u8;
+                    self.rom[offset + 1] = (target & 0xff) as u8;
+                }
+                Fixup::Unpack => {
+                    self.rom[offset + 1] |= ((target >> 8) & 0xf) as u8;
+                    self.rom[offset + 3] = (target & 0xff) as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.line = t.line;
+                self.pos += 1;
+                Ok(t.text.clone())
+            }
+            None => Err(String::from("unexpected end of program")),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let t = self.next()?;
+        if t != expected {
+            return Err(format!("expected '{}', got '{}'", expected, t));
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, b: u8) -> Result<(), String> {
+        if self.here >= RAM_SIZE {
+            return Err(String::from("program too large"));
+        }
+        let offset = self.here - PROGRAM_START;
+        if self.rom.len() <= offset {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = b;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, op: u16) -> Result<(), String> {
+        self.emit_byte((op >> 8) as u8)?;
+        self.emit_byte((op & 0xff) as u8)
+    }
+
+    fn patch_jump(&mut self, addr: usize, target: usize) {
+        let offset = addr - PROGRAM_START;
+        self.rom[offset] = 0x10 | ((target >> 8) & 0xf) as u8;
+        self.rom[offset + 1] = (target & 0xff) as u8;
+    }
+
+    fn register(&self, t: &str) -> Option<usize> {
+        if let Some(r) = self.aliases.get(t) {
+            return Some(*r);
+        }
+        let lower = t.to_lowercase();
+        let digit = lower.strip_prefix('v')?;
+        if digit.len() != 1 {
+            return None;
+        }
+        usize::from_str_radix(digit, 16).ok()
+    }
+
+    fn expect_register(&mut self) -> Result<usize, String> {
+        let t = self.next()?;
+        match self.register(&t) {
+            Some(r) => Ok(r),
+            None => Err(format!("expected a register, got '{}'", t)),
+        }
+    }
+
+    // A compile-time value: a literal, constant, defined label or { calc }.
+    fn value(&mut self, t: &str) -> Result<Option<f64>, String> {
+        if t == "{" {
+            return self.calc().map(Some);
+        }
+        if let Some(n) = parse_number(t) {
+            return Ok(Some(n));
+        }
+        if let Some(n) = self.consts.get(t) {
+            return Ok(Some(*n));
+        }
+        if let Some(a) = self.labels.get(t) {
+            return Ok(Some(*a as f64));
+        }
+        Ok(None)
+    }
+
+    fn byte_value(&mut self) -> Result<u8, String> {
+        let t = self.next()?;
+        match self.value(&t)? {
+            Some(n) if (-128.0..256.0).contains(&n) => Ok((n as i64 & 0xff) as u8),
+            Some(n) => Err(format!("value out of range: {}", n)),
+            None => Err(format!("undefined name: {}", t)),
+        }
+    }
+
+    fn nibble_value(&mut self) -> Result<u16, String> {
+        let t = self.next()?;
+        match self.value(&t)? {
+            Some(n) if (0.0..16.0).contains(&n) => Ok(n as u16),
+            Some(n) => Err(format!("value out of range: {}", n)),
+            None => Err(format!("undefined name: {}", t)),
+        }
+    }
+
+    // Emits an instruction whose low 12 bits are an address, which may be a
+    // label defined further down.
+    fn emit_address(&mut self, op: u16) -> Result<(), String> {
+        let t = self.next()?;
+        match self.value(&t)? {
+            Some(n) if (0.0..RAM_SIZE as f64).contains(&n) => self.emit(op | n as u16),
+            Some(n) => Err(format!("address out of range: {}", n)),
+            None => {
+                self.fixups.push((t, self.here, Fixup::Address));
+                self.emit(op)
+            }
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let t = self.next()?;
+        if let Some(r) = self.register(&t) {
+            return Ok(Operand::Register(r));
+        }
+        self.pos -= 1;
+        Ok(Operand::Value(self.byte_value()?))
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let x = self.expect_register()?;
+        let op = self.next()?;
+        let cond = match op.as_str() {
+            "key" => Condition::Key(x),
+            "-key" => Condition::NotKey(x),
+            "==" => Condition::Equal(x, self.operand()?),
+            "!=" => Condition::NotEqual(x, self.operand()?),
+            "<" => Condition::Less(x, self.operand()?),
+            ">" => Condition::Greater(x, self.operand()?),
+            "<=" => Condition::LessEqual(x, self.operand()?),
+            ">=" => Condition::GreaterEqual(x, self.operand()?),
+            _ => {
+                return Err(format!("unknown comparison: {}", op));
+            }
+        };
+        Ok(cond)
+    }
+
+    fn load_vf(&mut self, operand: &Operand) -> Result<(), String> {
+        match operand {
+            Operand::Register(y) => self.emit(0x8f00 | ((*y as u16) << 4)),
+            Operand::Value(n) => self.emit(0x6f00 | *n as u16),
+        }
+    }
+
+    // Emits instructions that skip the following one when the condition
+    // is false, or when it is true if `negate` is set.
+    fn emit_skip(&mut self, cond: &Condition, negate: bool) -> Result<(), String> {
+        // (skip when true, skip when false)
+        let (when_true, when_false): (u16, u16) = match cond {
+            Condition::Equal(x, Operand::Value(n)) => (
+                0x3000 | ((*x as u16) << 8) | *n as u16,
+                0x4000 | ((*x as u16) << 8) | *n as u16,
+            ),
+            Condition::NotEqual(x, Operand::Value(n)) => (
+                0x4000 | ((*x as u16) << 8) | *n as u16,
+                0x3000 | ((*x as u16) << 8) | *n as u16,
+            ),
+            Condition::Equal(x, Operand::Register(y)) => (
+                0x5000 | ((*x as u16) << 8) | ((*y as u16) << 4),
+                0x9000 | ((*x as u16) << 8) | ((*y as u16) << 4),
+            ),
+            Condition::NotEqual(x, Operand::Register(y)) => (
+                0x9000 | ((*x as u16) << 8) | ((*y as u16) << 4),
+                0x5000 | ((*x as u16) << 8) | ((*y as u16) << 4),
+            ),
+            Condition::Key(x) => (0xe09e | ((*x as u16) << 8), 0xe0a1 | ((*x as u16) << 8)),
+            Condition::NotKey(x) => (0xe0a1 | ((*x as u16) << 8), 0xe09e | ((*x as u16) << 8)),
+            // Comparisons subtract through vf, which is left at 1 when there
+            // was no borrow: vx - operand for < and >=, operand - vx for > and <=.
+            Condition::Less(x, o) | Condition::GreaterEqual(x, o) => {
+                self.load_vf(o)?;
+                self.emit(0x8f07 | ((*x as u16) << 4))?;
+                if matches!(cond, Condition::Less(_, _)) {
+                    (0x4f01, 0x3f01)
+                } else {
+                    (0x3f01, 0x4f01)
+                }
+            }
+            Condition::Greater(x, o) | Condition::LessEqual(x, o) => {
+                self.load_vf(o)?;
+                self.emit(0x8f05 | ((*x as u16) << 4))?;
+                if matches!(cond, Condition::Greater(_, _)) {
+                    (0x4f01, 0x3f01)
+                } else {
+                    (0x3f01, 0x4f01)
+                }
+            }
+        };
+        self.emit(if negate { when_true } else { when_false })
+    }
+
+    fn calc(&mut self) -> Result<f64, String> {
+        let n = self.expression()?;
+        self.expect("}")?;
+        Ok(n)
+    }
+
+    // Octo evaluates :calc expressions right to left without precedence.
+    fn expression(&mut self) -> Result<f64, String> {
+        let lhs = self.term()?;
+        let op = match self.peek() {
+            Some("}") | Some(")") | None => {
+                return Ok(lhs);
+            }
+            Some(op) => String::from(op),
+        };
+        self.next()?;
+        let rhs = self.expression()?;
+        let b = |v: bool| if v { 1.0 } else { 0.0 };
+        let ret = match op.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => (lhs as i64 & rhs as i64) as f64,
+            "|" => (lhs as i64 | rhs as i64) as f64,
+            "^" => (lhs as i64 ^ rhs as i64) as f64,
+            "<<" => ((lhs as i64) << (rhs as i64)) as f64,
+            ">>" => ((lhs as i64) >> (rhs as i64)) as f64,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => b(lhs < rhs),
+            ">" => b(lhs > rhs),
+            "<=" => b(lhs <= rhs),
+            ">=" => b(lhs >= rhs),
+            "==" => b(lhs == rhs),
+            "!=" => b(lhs != rhs),
+            _ => {
+                return Err(format!("unknown operator: {}", op));
+            }
+        };
+        Ok(ret)
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let t = self.next()?;
+        let ret = match t.as_str() {
+            "(" => {
+                let n = self.expression()?;
+                self.expect(")")?;
+                n
+            }
+            "-" => -self.term()?,
+            "~" => !(self.term()? as i64) as f64,
+            "!" => {
+                if self.term()? == 0.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            "floor" => self.term()?.floor(),
+            "ceil" => self.term()?.ceil(),
+            "abs" => self.term()?.abs(),
+            "sqrt" => self.term()?.sqrt(),
+            "sin" => self.term()?.sin(),
+            "cos" => self.term()?.cos(),
+            "HERE" => self.here as f64,
+            "PI" => std::f64::consts::PI,
+            "E" => std::f64::consts::E,
+            _ => match self.value(&t)? {
+                Some(n) => n,
+                None => {
+                    return Err(format!("undefined name in expression: {}", t));
+                }
+            },
+        };
+        Ok(ret)
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.next()?;
+        let mut args = Vec::new();
+        loop {
+            let t = self.next()?;
+            if t == "{" {
+                break;
+            }
+            args.push(t);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let t = self.tokens.get(self.pos).cloned();
+            let t = match t {
+                Some(t) => t,
+                None => {
+                    return Err(format!("unterminated macro: {}", name));
+                }
+            };
+            self.pos += 1;
+            if t.text == "{" {
+                depth += 1;
+            } else if t.text == "}" {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            body.push(t);
+        }
+        self.macros.insert(name, (args, body));
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), String> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return Err(String::from("too many macro expansions"));
+        }
+        let (args, body) = self.macros[name].clone();
+        let mut values = HashMap::new();
+        for a in args {
+            values.insert(a, self.next()?);
+        }
+        let expanded: Vec<Token> = body
+            .into_iter()
+            .map(|t| match values.get(&t.text) {
+                Some(v) => Token {
+                    text: v.clone(),
+                    line: t.line,
+                },
+                None => t,
+            })
+            .collect();
+        self.tokens.splice(self.pos..self.pos, expanded);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let t = self.next()?;
+
+        if UNSUPPORTED.contains(&t.as_str()) {
+            return Err(format!("'{}' is not supported on CHIP-8", t));
+        }
+        if self.macros.contains_key(&t) {
+            return self.expand_macro(&t);
+        }
+        if let Some(x) = self.register(&t) {
+            return self.register_statement(x);
+        }
+
+        match t.as_str() {
+            ":" => {
+                let name = self.next()?;
+                if self.labels.contains_key(&name) {
+                    return Err(format!("label redefined: {}", name));
+                }
+                self.labels.insert(name, self.here);
+            }
+            ":const" => {
+                let name = self.next()?;
+                let t = self.next()?;
+                match self.value(&t)? {
+                    Some(n) => self.consts.insert(name, n),
+                    None => {
+                        return Err(format!("undefined name: {}", t));
+                    }
+                };
+            }
+            ":calc" => {
+                let name = self.next()?;
+                self.expect("{")?;
+                let n = self.calc()?;
+                self.consts.insert(name, n);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let r = self.expect_register()?;
+                self.aliases.insert(name, r);
+            }
+            ":org" => {
+                let t = self.next()?;
+                match self.value(&t)? {
+                    Some(n) if (PROGRAM_START as f64..RAM_SIZE as f64).contains(&n) => {
+                        self.here = n as usize;
+                    }
+                    _ => {
+                        return Err(format!("invalid :org address: {}", t));
+                    }
+                }
+            }
+            ":byte" => {
+                let b = self.byte_value()?;
+                self.emit_byte(b)?;
+            }
+            ":call" => self.emit_address(0x2000)?,
+            ":unpack" => {
+                let nibble = self.nibble_value()?;
+                let t = self.next()?;
+                let addr = match self.value(&t)? {
+                    Some(n) => n as usize,
+                    None => {
+                        self.fixups.push((t, self.here, Fixup::Unpack));
+                        0
+                    }
+                };
+                self.emit(0x6000 | (nibble << 4) | ((addr >> 8) & 0xf) as u16)?;
+                self.emit(0x6100 | (addr & 0xff) as u16)?;
+            }
+            ":macro" => self.define_macro()?,
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            "clear" => self.emit(0x00e0)?,
+            "return" | ";" => self.emit(0x00ee)?,
+            "bcd" => {
+                let x = self.expect_register()?;
+                self.emit(0xf033 | ((x as u16) << 8))?;
+            }
+            "save" => {
+                let x = self.expect_register()?;
+                self.emit(0xf055 | ((x as u16) << 8))?;
+            }
+            "load" => {
+                let x = self.expect_register()?;
+                self.emit(0xf065 | ((x as u16) << 8))?;
+            }
+            "sprite" => {
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let n = self.nibble_value()?;
+                self.emit(0xd000 | ((x as u16) << 8) | ((y as u16) << 4) | n)?;
+            }
+            "jump" => self.emit_address(0x1000)?,
+            "jump0" => self.emit_address(0xb000)?,
+            "native" => self.emit_address(0x0000)?,
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.expect_register()?;
+                let op = if t == "delay" { 0xf015 } else { 0xf018 };
+                self.emit(op | ((x as u16) << 8))?;
+            }
+            "i" => {
+                let op = self.next()?;
+                match op.as_str() {
+                    ":=" => {
+                        if self.peek() == Some("hex") {
+                            self.next()?;
+                            let x = self.expect_register()?;
+                            self.emit(0xf029 | ((x as u16) << 8))?;
+                        } else {
+                            self.emit_address(0xa000)?;
+                        }
+                    }
+                    "+=" => {
+                        let x = self.expect_register()?;
+                        self.emit(0xf01e | ((x as u16) << 8))?;
+                    }
+                    _ => {
+                        return Err(format!("unknown operator for i: {}", op));
+                    }
+                }
+            }
+            "if" => {
+                let cond = self.condition()?;
+                let t = self.next()?;
+                match t.as_str() {
+                    "then" => self.emit_skip(&cond, false)?,
+                    "begin" => {
+                        self.emit_skip(&cond, true)?;
+                        self.branches.push(self.here);
+                        self.emit(0x1000)?;
+                    }
+                    _ => {
+                        return Err(format!("expected 'then' or 'begin', got '{}'", t));
+                    }
+                }
+            }
+            "else" => {
+                let addr = match self.branches.pop() {
+                    Some(a) => a,
+                    None => {
+                        return Err(String::from("'else' without 'begin'"));
+                    }
+                };
+                self.branches.push(self.here);
+                self.emit(0x1000)?;
+                self.patch_jump(addr, self.here);
+            }
+            "end" => match self.branches.pop() {
+                Some(addr) => self.patch_jump(addr, self.here),
+                None => {
+                    return Err(String::from("'end' without 'begin'"));
+                }
+            },
+            "loop" => self.loops.push((self.here, Vec::new())),
+            "while" => {
+                let cond = self.condition()?;
+                self.emit_skip(&cond, true)?;
+                let here = self.here;
+                match self.loops.last_mut() {
+                    Some((_, exits)) => exits.push(here),
+                    None => {
+                        return Err(String::from("'while' outside of a loop"));
+                    }
+                }
+                self.emit(0x1000)?;
+            }
+            "again" => {
+                let (start, exits) = match self.loops.pop() {
+                    Some(l) => l,
+                    None => {
+                        return Err(String::from("'again' without 'loop'"));
+                    }
+                };
+                self.emit(0x1000 | start as u16)?;
+                for e in exits {
+                    self.patch_jump(e, self.here);
+                }
+            }
+            // A label is called, whether or not it is defined yet.
+            _ if self.labels.contains_key(&t) => {
+                self.pos -= 1;
+                self.emit_address(0x2000)?;
+            }
+            _ => match self.value(&t)? {
+                Some(n) => {
+                    if !(-128.0..256.0).contains(&n) {
+                        return Err(format!("byte out of range: {}", n));
+                    }
+                    self.emit_byte((n as i64 & 0xff) as u8)?;
+                }
+                // Anything else is a call to a label defined further down.
+                None => {
+                    self.pos -= 1;
+                    self.emit_address(0x2000)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: usize) -> Result<(), String> {
+        let x16 = (x as u16) << 8;
+        let op = self.next()?;
+        let rhs = self.next()?;
+        let y = self.register(&rhs);
+
+        match (op.as_str(), y) {
+            (":=", _) if rhs == "delay" => self.emit(0xf007 | x16),
+            (":=", _) if rhs == "key" => self.emit(0xf00a | x16),
+            (":=", _) if rhs == "random" => {
+                let n = self.byte_value()?;
+                self.emit(0xc000 | x16 | n as u16)
+            }
+            (":=", Some(y)) => self.emit(0x8000 | x16 | ((y as u16) << 4)),
+            ("+=", Some(y)) => self.emit(0x8004 | x16 | ((y as u16) << 4)),
+            ("-=", Some(y)) => self.emit(0x8005 | x16 | ((y as u16) << 4)),
+            ("=-", Some(y)) => self.emit(0x8007 | x16 | ((y as u16) << 4)),
+            ("|=", Some(y)) => self.emit(0x8001 | x16 | ((y as u16) << 4)),
+            ("&=", Some(y)) => self.emit(0x8002 | x16 | ((y as u16) << 4)),
+            ("^=", Some(y)) => self.emit(0x8003 | x16 | ((y as u16) << 4)),
+            (">>=", Some(y)) => self.emit(0x8006 | x16 | ((y as u16) << 4)),
+            ("<<=", Some(y)) => self.emit(0x800e | x16 | ((y as u16) << 4)),
+            (":=", None) | ("+=", None) | ("-=", None) => {
+                self.pos -= 1;
+                let n = self.byte_value()?;
+                match op.as_str() {
+                    ":=" => self.emit(0x6000 | x16 | n as u16),
+                    "+=" => self.emit(0x7000 | x16 | n as u16),
+                    _ => self.emit(0x7000 | x16 | n.wrapping_neg() as u16),
+                }
+            }
+            _ => Err(format!("invalid operation: v{:x} {} {}", x, op, rhs)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(rom: &[u8]) -> Vec<u16> {
+        rom.chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]))
+            .collect()
+    }
+
+    #[test]
+    fn labels_forward_and_backward() {
+        let rom = assemble(
+            ": main
+               v0 := 5
+               draw        # a call to a label further down
+               jump main
+             : draw
+               i := sprite
+               sprite v0 v0 1
+               return
+             : sprite 0x80",
+        )
+        .unwrap();
+        assert_eq!(
+            words(&rom),
+            [0x1202, 0x6005, 0x2208, 0x1202, 0xa20e, 0xd001, 0x00ee, 0x8000]
+        );
+        assert_eq!(rom.len(), 15);
+    }
+
+    #[test]
+    fn main_need_not_come_first() {
+        let rom = assemble(": helper return : main helper").unwrap();
+        assert_eq!(words(&rom), [0x1204, 0x00ee, 0x2202]);
+    }
+
+    #[test]
+    fn unpack_splits_an_address_over_v0_and_v1() {
+        let rom = assemble(": main :unpack 0xA data ; : data 0x12").unwrap();
+        assert_eq!(words(&rom), [0x1202, 0x60a2, 0x6108, 0x00ee, 0x1200]);
+
+        let rom = assemble(": data 0x12 : main :unpack 1 data").unwrap();
+        assert_eq!(words(&rom), [0x1203, 0x1260, 0x1261, 0x0200]);
+    }
+
+    #[test]
+    fn if_then_skips_the_next_instruction_unless_true() {
+        let rom = assemble(
+            ": main
+               if v1 == 3 then v2 := 1
+               if v1 != v2 then v2 := 1
+               if v3 key then v2 := 1
+               if v3 -key then v2 := 1
+               if v1 < 5 then v2 := 1",
+        )
+        .unwrap();
+        assert_eq!(
+            words(&rom),
+            [
+                0x1202, 0x4103, 0x6201, 0x5120, 0x6201, 0xe3a1, 0x6201, 0xe39e, 0x6201, 0x6f05,
+                0x8f17, 0x3f01, 0x6201
+            ]
+        );
+    }
+
+    #[test]
+    fn if_begin_else_end_and_loops() {
+        let rom = assemble(
+            ": main
+               if v0 == 1 begin v1 := 1 else v1 := 2 end
+               loop
+                 v0 += 1
+                 while v0 != 10
+               again",
+        )
+        .unwrap();
+        assert_eq!(
+            words(&rom),
+            [0x1202, 0x3001, 0x120a, 0x6101, 0x120c, 0x6102, 0x7001, 0x400a, 0x1214, 0x120c]
+        );
+    }
+
+    #[test]
+    fn macros_consts_and_calc() {
+        let rom = assemble(
+            ":const SPEED 3
+             :calc DOUBLE { SPEED + SPEED }
+             :alias x v4
+             :macro bump reg n { reg += n }
+             : main
+               bump v3 SPEED
+               bump x DOUBLE",
+        )
+        .unwrap();
+        assert_eq!(words(&rom), [0x1202, 0x7303, 0x7406]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(
+            assemble(": main\n  hires").unwrap_err(),
+            "line 2: 'hires' is not supported on CHIP-8"
+        );
+        assert_eq!(
+            assemble(": main\n  jump nowhere").unwrap_err(),
+            "line 2: undefined label: nowhere"
+        );
+        assert!(assemble(": main loop v0 += 1").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use crate::audio::{AudioConfig, Waveform};
+use crate::cpu::RAM_SIZE;
+
+pub const USAGE: &str = "[options] <rom-file-name>
+
+Audio options:
+  --tone <hz>          buzzer frequency (default 440)
+  --wave <shape>       sine, square or triangle (default sine)
+  --volume <0-1>       buzzer volume (default 1)
+  --attack <ms>        envelope attack time (default 5)
+  --release <ms>       envelope release time (default 5)
+  --no-audio           do not open an audio device
+  --wav <file>         render the buzzer to a WAV file instead of playing it
+
+Timing options:
+  --vip                charge each instruction its COSMAC VIP cycle cost and make
+                       DXYN wait for vertical blank, instead of a fixed number of
+                       instructions per frame
+
+Netplay options:
+  --host [<addr>:]<port>
+                       wait for a second player to join on this TCP port, on
+                       localhost unless an address such as 0.0.0.0 is given
+  --join <host:port>   join a game hosted by another instance
+  --seed <n>           seed for the random number generator (the host's is shared)
+
+Scripting options:
+  --script <file>      load a Rhai script with frame, PC and memory write hooks
+
+Debugging options:
+  --trace              print every executed instruction
+  --cheats             enable the cheat console on stdin
+  --cheats-dir <dir>   where cheat lists are kept (default: cheats)
+
+Debugger options:
+  --gdb <port>         wait for a GDB or LLDB client on localhost before running
+
+Headless options:
+  --headless           run without a window or audio, then print the final state
+  --frames <n>         stop after this many frames (default 600)
+  --until-pc <hex>     stop when PC reaches this address
+  --until-opcode <hex> stop before this opcode runs; x matches any digit, e.g. 1xxx
+  --out <file>         save the final display as text, or PNG if it ends in .png";
+
+pub struct Options {
+    pub rom: String,
+    pub audio: AudioConfig,
+    pub no_audio: bool,
+    pub wav: Option<String>,
+    pub vip: bool,
+    // The address to listen on, localhost unless given.
+    pub host: Option<String>,
+    pub join: Option<String>,
+    pub seed: Option<u64>,
+    pub script: Option<String>,
+    pub trace: bool,
+    pub cheats: bool,
+    pub cheats_dir: String,
+    pub gdb: Option<u16>,
+    pub headless: bool,
+    pub frames: u64,
+    pub until_pc: Option<usize>,
+    pub until_opcode: Option<(u16, u16)>,
+    pub out: Option<String>,
+}
+
+fn parse_number(flag: &str, value: Option<&String>) -> Result<f32, String> {
+    let value = match value {
+        Some(v) => v,
+        None => {
+            return Err(format!("missing value for {}", flag));
+        }
+    };
+    match value.parse::<f32>() {
+        Ok(n) if n >= 0.0 => Ok(n),
+        _ => Err(format!("invalid value for {}: {}", flag, value)),
+    }
+}
+
+fn parse_integer(flag: &str, value: Option<&String>) -> Result<u64, String> {
+    let value = match value {
+        Some(v) => v,
+        None => {
+            return Err(format!("missing value for {}", flag));
+        }
+    };
+    match value.parse::<u64>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("invalid value for {}: {}", flag, value)),
+    }
+}
+
+fn parse_address(flag: &str, value: Option<&String>) -> Result<usize, String> {
+    let value = parse_string(flag, value)?;
+    match usize::from_str_radix(value.trim_start_matches("0x"), 16) {
+        Ok(n) if n < RAM_SIZE => Ok(n),
+        _ => Err(format!("invalid value for {}: {}", flag, value)),
+    }
+}
+
+// An opcode pattern such as "00e0" or "dxyn": every digit that is not hex is a
+// wildcard. Returns the value and the mask to compare it under.
+fn parse_opcode(flag: &str, value: Option<&String>) -> Result<(u16, u16), String> {
+    let value = parse_string(flag, value)?;
+    let digits = value.trim_start_matches("0x");
+    if digits.chars().count() != 4 {
+        return Err(format!("invalid value for {}: {}", flag, value));
+    }
+    let mut opcode = 0;
+    let mut mask = 0;
+    for c in digits.chars() {
+        opcode <<= 4;
+        mask <<= 4;
+        if let Some(d) = c.to_digit(16) {
+            opcode |= d as u16;
+            mask |= 0xf;
+        }
+    }
+    Ok((opcode, mask))
+}
+
+fn parse_string(flag: &str, value: Option<&String>) -> Result<String, String> {
+    match value {
+        Some(v) => Ok(v.clone()),
+        None => Err(format!("missing value for {}", flag)),
+    }
+}
+
+// A bare port listens on localhost only; other players on the network need an
+// explicit address.
+fn parse_host(flag: &str, value: Option<&String>) -> Result<String, String> {
+    let value = parse_string(flag, value)?;
+    if let Ok(port) = value.parse::<u16>() {
+        return Ok(format!("127.0.0.1:{}", port));
+    }
+    match value.parse::<std::net::SocketAddr>() {
+        Ok(addr) => Ok(addr.to_string()),
+        Err(_) => Err(format!("invalid value for {}: {}", flag, value)),
+    }
+}
+
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut rom = None;
+    let mut audio = AudioConfig::default();
+    let mut no_audio = false;
+    let mut wav = None;
+    let mut vip = false;
+    let mut host = None;
+    let mut join = None;
+    let mut seed = None;
+    let mut script = None;
+    let mut trace = false;
+    let mut cheats = false;
+    let mut cheats_dir = String::from("cheats");
+    let mut gdb = None;
+    let mut headless = false;
+    let mut frames = 600;
+    let mut until_pc = None;
+    let mut until_opcode = None;
+    let mut out = None;
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--tone" => audio.frequency = parse_number(arg, iter.next())?,
+            "--wave" => audio.waveform = Waveform::parse(&parse_string(arg, iter.next())?)?,
+            "--volume" => audio.volume = parse_number(arg, iter.next())?.min(1.0),
+            "--attack" => audio.attack_ms = parse_number(arg, iter.next())?,
+            "--release" => audio.release_ms = parse_number(arg, iter.next())?,
+            "--no-audio" => no_audio = true,
+            "--wav" => wav = Some(parse_string(arg, iter.next())?),
+            "--vip" => vip = true,
+            "--host" => host = Some(parse_host(arg, iter.next())?),
+            "--join" => join = Some(parse_string(arg, iter.next())?),
+            "--seed" => seed = Some(parse_integer(arg, iter.next())?),
+            "--script" => script = Some(parse_string(arg, iter.next())?),
+            "--trace" => trace = true,
+            "--cheats" => cheats = true,
+            "--cheats-dir" => cheats_dir = parse_string(arg, iter.next())?,
+            "--gdb" => match u16::try_from(parse_integer(arg, iter.next())?) {
+                Ok(port) => gdb = Some(port),
+                Err(_) => {
+                    return Err(String::from("invalid value for --gdb"));
+                }
+            },
+            "--headless" => headless = true,
+            "--frames" => frames = parse_integer(arg, iter.next())?,
+            "--until-pc" => until_pc = Some(parse_address(arg, iter.next())?),
+            "--until-opcode" => until_opcode = Some(parse_opcode(arg, iter.next())?),
+            "--out" => out = Some(parse_string(arg, iter.next())?),
+            _ if arg.starts_with("--") => {
+                return Err(format!("unknown option: {}", arg));
+            }
+            _ => {
+                if rom.is_some() {
+                    return Err(format!("unexpected argument: {}", arg));
+                }
+                rom = Some(arg.clone());
+            }
+        }
+    }
+
+    if host.is_some() && join.is_some() {
+        return Err(String::from("--host and --join cannot be used together"));
+    }
+    if gdb.is_some() && headless {
+        return Err(String::from("--gdb and --headless cannot be used together"));
+    }
+
+    match rom {
+        Some(rom) => Ok(Options {
+            rom,
+            audio,
+            no_audio,
+            wav,
+            vip,
+            host,
+            join,
+            seed,
+            script,
+            trace,
+            cheats,
+            cheats_dir,
+            gdb,
+            headless,
+            frames,
+            until_pc,
+            until_opcode,
+            out,
+        }),
+        None => Err(String::from("missing ROM file name")),
+    }
+}
@@ -0,0 +1,273 @@
+use std::fs;
+use std::io::{Cursor, Read};
+
+use serde_json::Value;
+
+use crate::cpu::Quirks;
+use crate::octo;
+
+const GIF_MAGIC: &[u8] = b"GIF8";
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const ROM_EXTENSIONS: [&str; 4] = ["ch8", "c8", "8o", "gif"];
+
+// Settings a ROM can carry with it; anything missing keeps the emulator default.
+#[derive(Default)]
+pub struct RomOptions {
+    pub tickrate: Option<usize>,
+    pub palette: Option<(u32, u32)>,
+    pub quirks: Option<Quirks>,
+}
+
+pub struct Rom {
+    pub data: Vec<u8>,
+    pub options: RomOptions,
+}
+
+pub fn load(filename: &str) -> Result<Rom, String> {
+    let bytes = match fs::read(filename) {
+        Ok(b) => b,
+        Err(err) => {
+            return Err(err.to_string());
+        }
+    };
+    decode(filename, bytes)
+}
+
+fn decode(name: &str, bytes: Vec<u8>) -> Result<Rom, String> {
+    if bytes.starts_with(GIF_MAGIC) {
+        return load_cartridge(&bytes);
+    }
+    if bytes.starts_with(ZIP_MAGIC) {
+        return load_zip(bytes);
+    }
+    if name.to_lowercase().ends_with(".8o") {
+        let source = match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(err) => {
+                return Err(err.to_string());
+            }
+        };
+        return Ok(Rom {
+            data: octo::assemble(&source)?,
+            options: RomOptions::default(),
+        });
+    }
+    Ok(Rom {
+        data: bytes,
+        options: RomOptions::default(),
+    })
+}
+
+// Opens the first file in the archive with a known ROM extension.
+fn load_zip(bytes: Vec<u8>) -> Result<Rom, String> {
+    let mut archive = match zip::ZipArchive::new(Cursor::new(bytes)) {
+        Ok(a) => a,
+        Err(err) => {
+            return Err(err.to_string());
+        }
+    };
+    for j in 0..archive.len() {
+        let mut file = match archive.by_index(j) {
+            Ok(f) => f,
+            Err(err) => {
+                return Err(err.to_string());
+            }
+        };
+        let name = String::from(file.name());
+        let extension = name.rsplit('.').next().unwrap_or("").to_lowercase();
+        if !file.is_file() || !ROM_EXTENSIONS.contains(&extension.as_str()) {
+            continue;
+        }
+        let mut contents = Vec::new();
+        if let Err(err) = file.read_to_end(&mut contents) {
+            return Err(err.to_string());
+        }
+        return decode(&name, contents);
+    }
+    Err(String::from("no ROM found in zip archive"))
+}
+
+// Octo cartridges hide their payload in the low nibble of each pixel's palette
+// index, across all frames in order: a 32-bit big-endian length followed by a
+// UTF-8 JSON object holding the program source and its options.
+fn load_cartridge(bytes: &[u8]) -> Result<Rom, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = match options.read_info(bytes) {
+        Ok(d) => d,
+        Err(err) => {
+            return Err(err.to_string());
+        }
+    };
+
+    let mut nibbles = Vec::new();
+    loop {
+        match decoder.read_next_frame() {
+            Ok(Some(frame)) => nibbles.extend(frame.buffer.iter().map(|p| p & 0xf)),
+            Ok(None) => break,
+            Err(err) => {
+                return Err(err.to_string());
+            }
+        }
+    }
+    let payload: Vec<u8> = nibbles
+        .chunks_exact(2)
+        .map(|n| (n[0] << 4) | n[1])
+        .collect();
+
+    if payload.len() < 4 {
+        return Err(String::from("cartridge has no payload"));
+    }
+    let size = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    if payload.len() < 4 + size {
+        return Err(String::from("cartridge payload is truncated"));
+    }
+    let json: Value = match serde_json::from_slice(&payload[4..4 + size]) {
+        Ok(v) => v,
+        Err(err) => {
+            return Err(format!("invalid cartridge payload: {}", err));
+        }
+    };
+
+    let source = match json["program"].as_str() {
+        Some(s) => s,
+        None => {
+            return Err(String::from("cartridge has no program"));
+        }
+    };
+    Ok(Rom {
+        data: octo::assemble(source)?,
+        options: parse_octo_options(&json["options"]),
+    })
+}
+
+fn parse_color(value: &Value) -> Option<u32> {
+    let s = value.as_str()?.strip_prefix('#')?;
+    u32::from_str_radix(s, 16).ok()
+}
+
+fn parse_octo_options(options: &Value) -> RomOptions {
+    let tickrate = options["tickrate"].as_u64().map(|t| t as usize);
+
+    let palette = match (
+        parse_color(&options["fillColor"]),
+        parse_color(&options["backgroundColor"]),
+    ) {
+        (Some(on), Some(off)) => Some((on, off)),
+        _ => None,
+    };
+
+    // Octo runs with every quirk off unless the cartridge turns it on.
+    let mut quirks = Quirks {
+        shift: false,
+        load_store: false,
+        jump: false,
+        logic: false,
+        clip: false,
+        vblank: false,
+    };
+    let mut has_quirks = false;
+    let mut flag = |key: &str, field: &mut bool| {
+        if let Some(b) = options[key].as_bool() {
+            *field = b;
+            has_quirks = true;
+        }
+    };
+    flag("shiftQuirks", &mut quirks.shift);
+    flag("loadStoreQuirks", &mut quirks.load_store);
+    flag("jumpQuirks", &mut quirks.jump);
+    flag("logicQuirks", &mut quirks.logic);
+    flag("clipQuirks", &mut quirks.clip);
+    flag("vBlankQuirks", &mut quirks.vblank);
+
+    RomOptions {
+        tickrate,
+        palette,
+        quirks: if has_quirks { Some(quirks) } else { None },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    const SOURCE: &str = ": main v0 := 7";
+    const ASSEMBLED: [u8; 4] = [0x12, 0x02, 0x60, 0x07];
+
+    fn write(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("chip8_rom_{}_{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn load_file(name: &str, bytes: &[u8]) -> Result<Rom, String> {
+        let path = write(name, bytes);
+        let rom = load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        rom
+    }
+
+    // A one-row GIF whose palette indices carry the payload, a nibble each.
+    fn cartridge(json: &str) -> Vec<u8> {
+        let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(json.as_bytes());
+        let pixels: Vec<u8> = payload.iter().flat_map(|b| [b >> 4, b & 0xf]).collect();
+        let palette: Vec<u8> = (0..16).flat_map(|j| [j * 16, j * 16, j * 16]).collect();
+        let mut gif = Vec::new();
+        {
+            let width = pixels.len() as u16;
+            let mut encoder = gif::Encoder::new(&mut gif, width, 1, &palette).unwrap();
+            let frame = gif::Frame::from_indexed_pixels(width, 1, pixels, None);
+            encoder.write_frame(&frame).unwrap();
+        }
+        gif
+    }
+
+    #[test]
+    fn raw_binary() {
+        let rom = load_file("raw.ch8", &[0x00, 0xe0, 0x12, 0x00]).unwrap();
+        assert_eq!(rom.data, [0x00, 0xe0, 0x12, 0x00]);
+        assert!(rom.options.quirks.is_none());
+    }
+
+    #[test]
+    fn octo_source() {
+        let rom = load_file("source.8o", SOURCE.as_bytes()).unwrap();
+        assert_eq!(rom.data, ASSEMBLED);
+    }
+
+    #[test]
+    fn zip_archive() {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.start_file("README.txt", options).unwrap();
+        zip.write_all(b"not a rom").unwrap();
+        zip.start_file("game.8o", options).unwrap();
+        zip.write_all(SOURCE.as_bytes()).unwrap();
+        let bytes = zip.finish().unwrap().into_inner();
+
+        let rom = load_file("archive.zip", &bytes).unwrap();
+        assert_eq!(rom.data, ASSEMBLED);
+    }
+
+    #[test]
+    fn octo_cartridge() {
+        let json = r##"{"program": ": main v0 := 7", "options": {"tickrate": 20, "fillColor": "#FFAA00", "backgroundColor": "#000000", "shiftQuirks": true}}"##;
+        let rom = load_file("cart.gif", &cartridge(json)).unwrap();
+        assert_eq!(rom.data, ASSEMBLED);
+        assert_eq!(rom.options.tickrate, Some(20));
+        assert_eq!(rom.options.palette, Some((0xffaa00, 0x000000)));
+        // Quirks the cartridge leaves out are off, as in Octo.
+        let quirks = rom.options.quirks.unwrap();
+        assert!(quirks.shift);
+        assert!(!quirks.load_store);
+        assert!(!quirks.vblank);
+    }
+
+    #[test]
+    fn cartridge_without_a_program() {
+        assert!(load_file("empty.gif", &cartridge("{}")).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+use rhai::{Engine, FnPtr, Scope, AST, INT};
+
+use crate::cpu::{RAM_DIGITS, RAM_SIZE};
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 32;
+
+// The parts of the machine a script can see and change. The CPU copies its
+// state in before running callbacks and copies it back out afterwards.
+#[derive(Clone)]
+pub struct Machine {
+    pub ram: [u8; RAM_SIZE],
+    pub v: [u8; 16],
+    pub i: usize,
+    pub pc: usize,
+    pub dt: u8,
+    pub st: u8,
+    pub keys: [bool; 16],
+    pub frame: u32,
+}
+
+struct ScriptState {
+    machine: Machine,
+    frame_hooks: Vec<FnPtr>,
+    pc_hooks: Vec<(usize, FnPtr)>,
+    write_hooks: Vec<(usize, FnPtr)>,
+    held_keys: [bool; 16],
+    overlay: Vec<Option<u32>>,
+    // Whether the overlay was drawn on since the CPU last took it.
+    overlay_changed: bool,
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    state: Rc<RefCell<ScriptState>>,
+}
+
+fn hud_set(s: &mut ScriptState, x: INT, y: INT, color: Option<u32>) {
+    s.overlay_changed = true;
+    if (0..WIDTH as INT).contains(&x) && (0..HEIGHT as INT).contains(&y) {
+        s.overlay[y as usize * WIDTH + x as usize] = color;
+    }
+}
+
+impl Script {
+    pub fn load(path: &str, machine: Machine) -> Result<Script, String> {
+        match fs::read_to_string(path) {
+            Ok(source) => Script::compile(path, &source, machine),
+            Err(err) => Err(format!("could not read {}: {}", path, err)),
+        }
+    }
+
+    // `name` is only used in error messages.
+    pub fn compile(name: &str, source: &str, machine: Machine) -> Result<Script, String> {
+        let state = Rc::new(RefCell::new(ScriptState {
+            machine,
+            frame_hooks: Vec::new(),
+            pc_hooks: Vec::new(),
+            write_hooks: Vec::new(),
+            held_keys: [false; 16],
+            overlay: vec![None; WIDTH * HEIGHT],
+            overlay_changed: true,
+        }));
+
+        let mut engine = Engine::new();
+        Script::register_api(&mut engine, &state);
+
+        let ast = match engine.compile(source) {
+            Ok(a) => a,
+            Err(err) => {
+                return Err(format!("{}: {}", name, err));
+            }
+        };
+        // Top-level statements run once, which is where hooks get registered.
+        if let Err(err) = engine.run_ast_with_scope(&mut Scope::new(), &ast) {
+            return Err(format!("{}: {}", name, err));
+        }
+
+        Ok(Script { engine, ast, state })
+    }
+
+    fn register_api(engine: &mut Engine, state: &Rc<RefCell<ScriptState>>) {
+        let s = state.clone();
+        engine.register_fn("on_frame", move |f: FnPtr| {
+            s.borrow_mut().frame_hooks.push(f);
+        });
+        let s = state.clone();
+        engine.register_fn("on_pc", move |addr: INT, f: FnPtr| {
+            s.borrow_mut().pc_hooks.push((addr as usize, f));
+        });
+        let s = state.clone();
+        engine.register_fn("on_write", move |addr: INT, f: FnPtr| {
+            s.borrow_mut().write_hooks.push((addr as usize, f));
+        });
+
+        let s = state.clone();
+        engine.register_fn("reg", move |x: INT| -> INT {
+            s.borrow().machine.v[x as usize & 0xf] as INT
+        });
+        let s = state.clone();
+        engine.register_fn("set_reg", move |x: INT, val: INT| {
+            s.borrow_mut().machine.v[x as usize & 0xf] = val as u8;
+        });
+        let s = state.clone();
+        engine.register_fn("peek", move |addr: INT| -> INT {
+            s.borrow().machine.ram[addr as usize % RAM_SIZE] as INT
+        });
+        let s = state.clone();
+        engine.register_fn("poke", move |addr: INT, val: INT| {
+            s.borrow_mut().machine.ram[addr as usize % RAM_SIZE] = val as u8;
+        });
+        let s = state.clone();
+        engine.register_fn("get_i", move || -> INT { s.borrow().machine.i as INT });
+        let s = state.clone();
+        engine.register_fn("set_i", move |val: INT| {
+            s.borrow_mut().machine.i = val as usize % RAM_SIZE;
+        });
+        let s = state.clone();
+        engine.register_fn("get_pc", move || -> INT { s.borrow().machine.pc as INT });
+        let s = state.clone();
+        engine.register_fn("set_pc", move |val: INT| {
+            s.borrow_mut().machine.pc = val as usize % RAM_SIZE;
+        });
+        let s = state.clone();
+        engine.register_fn("get_delay", move || -> INT { s.borrow().machine.dt as INT });
+        let s = state.clone();
+        engine.register_fn("set_delay", move |val: INT| {
+            s.borrow_mut().machine.dt = val as u8;
+        });
+        let s = state.clone();
+        engine.register_fn("get_sound", move || -> INT { s.borrow().machine.st as INT });
+        let s = state.clone();
+        engine.register_fn("set_sound", move |val: INT| {
+            s.borrow_mut().machine.st = val as u8;
+        });
+        let s = state.clone();
+        engine.register_fn("frame", move || -> INT { s.borrow().machine.frame as INT });
+
+        let s = state.clone();
+        engine.register_fn("key", move |k: INT| -> bool {
+            s.borrow().machine.keys[k as usize & 0xf]
+        });
+        let s = state.clone();
+        engine.register_fn("press", move |k: INT| {
+            s.borrow_mut().held_keys[k as usize & 0xf] = true;
+        });
+        let s = state.clone();
+        engine.register_fn("release", move |k: INT| {
+            s.borrow_mut().held_keys[k as usize & 0xf] = false;
+        });
+
+        let s = state.clone();
+        engine.register_fn("hud_clear", move || {
+            let mut st = s.borrow_mut();
+            st.overlay.iter_mut().for_each(|p| *p = None);
+            st.overlay_changed = true;
+        });
+        let s = state.clone();
+        engine.register_fn("hud_pixel", move |x: INT, y: INT, color: INT| {
+            hud_set(&mut s.borrow_mut(), x, y, Some(color as u32));
+        });
+        let s = state.clone();
+        engine.register_fn("hud_number", move |x: INT, y: INT, n: INT, color: INT| {
+            let mut st = s.borrow_mut();
+            for (j, c) in n.to_string().chars().enumerate() {
+                let glyph = match c.to_digit(10) {
+                    Some(d) => RAM_DIGITS[d as usize],
+                    None => continue,
+                };
+                for (row, bits) in glyph.iter().enumerate() {
+                    for col in 0..4 {
+                        if bits & (0x80 >> col) != 0 {
+                            let px = x + (j as INT * 5) + col;
+                            hud_set(&mut st, px, y + row as INT, Some(color as u32));
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    fn call(&self, f: &FnPtr, args: Vec<INT>) -> Result<(), String> {
+        let result = match args.len() {
+            0 => f.call::<rhai::Dynamic>(&self.engine, &self.ast, ()),
+            _ => f.call::<rhai::Dynamic>(&self.engine, &self.ast, (args[0], args[1])),
+        };
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("script error in {}: {}", f.fn_name(), err)),
+        }
+    }
+
+    fn run_hooks(
+        &mut self,
+        machine: &mut Machine,
+        hooks: Vec<FnPtr>,
+        args: Vec<INT>,
+    ) -> Result<(), String> {
+        if hooks.is_empty() {
+            return Ok(());
+        }
+        self.state.borrow_mut().machine = machine.clone();
+        for f in hooks.iter() {
+            self.call(f, args.clone())?;
+        }
+        *machine = self.state.borrow().machine.clone();
+        Ok(())
+    }
+
+    pub fn has_frame_hooks(&self) -> bool {
+        !self.state.borrow().frame_hooks.is_empty()
+    }
+
+    pub fn frame_end(&mut self, machine: &mut Machine) -> Result<(), String> {
+        let hooks = self.state.borrow().frame_hooks.clone();
+        self.run_hooks(machine, hooks, Vec::new())
+    }
+
+    pub fn has_pc_hook(&self, pc: usize) -> bool {
+        self.state.borrow().pc_hooks.iter().any(|(a, _)| *a == pc)
+    }
+
+    pub fn pc_reached(&mut self, machine: &mut Machine) -> Result<(), String> {
+        let hooks = self
+            .state
+            .borrow()
+            .pc_hooks
+            .iter()
+            .filter(|(a, _)| *a == machine.pc)
+            .map(|(_, f)| f.clone())
+            .collect();
+        self.run_hooks(machine, hooks, Vec::new())
+    }
+
+    pub fn has_write_hook(&self, addr: usize) -> bool {
+        self.state
+            .borrow()
+            .write_hooks
+            .iter()
+            .any(|(a, _)| *a == addr)
+    }
+
+    pub fn memory_written(&mut self, machine: &mut Machine, addr: usize) -> Result<(), String> {
+        let hooks = self
+            .state
+            .borrow()
+            .write_hooks
+            .iter()
+            .filter(|(a, _)| *a == addr)
+            .map(|(_, f)| f.clone())
+            .collect();
+        let value = machine.ram[addr] as INT;
+        self.run_hooks(machine, hooks, vec![addr as INT, value])
+    }
+
+    pub fn held_keys(&self) -> [bool; 16] {
+        self.state.borrow().held_keys
+    }
+
+    // The overlay if the script drew on it since the last call.
+    pub fn take_overlay(&self) -> Option<Vec<Option<u32>>> {
+        let mut state = self.state.borrow_mut();
+        if !state.overlay_changed {
+            return None;
+        }
+        state.overlay_changed = false;
+        Some(state.overlay.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Audio;
+    use crate::cpu::CPU;
+    use crate::octo;
+    use crate::window::Window;
+
+    // Counts v0 up and stores it at `target` (0x20c) forever.
+    const ROM: &str = ": main
+          v0 := 1
+          loop
+            v0 += 1
+            i := target
+            save v0
+          again
+        : target 0";
+
+    fn run(script: &str, frames: usize) -> Machine {
+        let mut cpu = CPU::new(Window::headless(), Audio::null());
+        cpu.load_rom(&octo::assemble(ROM).unwrap()).unwrap();
+        let script = Script::compile("test", script, cpu.machine(&[false; 16])).unwrap();
+        cpu.set_script(script);
+        for _ in 0..frames {
+            cpu.run_frame().unwrap();
+        }
+        cpu.machine(&[false; 16])
+    }
+
+    #[test]
+    fn frame_hook_runs_once_per_frame() {
+        let machine = run(
+            "on_frame(|| { poke(0x300, peek(0x300) + 1); set_reg(5, frame()); });",
+            10,
+        );
+        assert_eq!(machine.ram[0x300], 10);
+        assert_eq!(machine.v[5], 10);
+    }
+
+    #[test]
+    fn write_hook_sees_the_written_value() {
+        let machine = run(
+            "on_write(0x20c, |addr, value| { poke(0x301, value); poke(0x302, addr - 0x200); });",
+            3,
+        );
+        assert_ne!(machine.ram[0x20c], 0);
+        assert_eq!(machine.ram[0x301], machine.ram[0x20c]);
+        assert_eq!(machine.ram[0x302], 0x0c);
+    }
+
+    #[test]
+    fn errors_name_the_script() {
+        let machine = CPU::new(Window::headless(), Audio::null()).machine(&[false; 16]);
+        let err = Script::compile("broken.rhai", "on_frame(", machine)
+            .err()
+            .unwrap();
+        assert!(err.starts_with("broken.rhai: "), "{}", err);
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use crate::util::get_hex_digits;
+
+// Emulated frames, and with them the timers, run at 60 Hz however often the
+// window refreshes.
+pub const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+// One 1802 machine cycle is 8 clocks of the VIP's 1.76 MHz crystal, so a
+// 60 Hz frame lasts about 3668 of them. The CDP1861 display steals 1024 of
+// those for DMA (8 bytes on each of 128 lines) and the interrupt routine that
+// runs the timers takes another 46, leaving the rest to the interpreter.
+pub const VIP_CYCLES_PER_FRAME: usize = 3668 - 1024 - 46;
+
+// How instructions are paced against the 60 Hz timers.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Timing {
+    // A fixed number of instructions per frame, whatever they are.
+    Fixed,
+    // Every instruction costs what it took the original COSMAC VIP
+    // interpreter, and DXYN waits for the next vertical blank.
+    Vip,
+}
+
+// Approximate cost of an instruction on the COSMAC VIP in machine cycles,
+// including the interpreter's fetch and decode. Some depend on the operands,
+// so the registers are needed as they are before the instruction runs.
+pub fn vip_cycles(instruction: u16, v: &[u8; 16]) -> usize {
+    let x = get_hex_digits(&instruction, 1, 2);
+    let n = get_hex_digits(&instruction, 1, 0);
+    let vx = v[x];
+    match instruction {
+        0x00e0 => 24,
+        0x00ee => 23,
+        0x0000..=0x0fff => 23,
+        0x1000..=0x2fff => 23,
+        0x3000..=0x4fff => 12,
+        0x5000..=0x5fff => 16,
+        0x6000..=0x6fff => 6,
+        0x7000..=0x7fff => 10,
+        0x8000..=0x8fff => 44,
+        0x9000..=0x9fff => 16,
+        0xa000..=0xafff => 12,
+        0xb000..=0xbfff => 23,
+        0xc000..=0xcfff => 36,
+        // Sprites that are not byte-aligned have to be shifted into place.
+        0xd000..=0xdfff => {
+            let row = if vx.is_multiple_of(8) { 14 } else { 21 };
+            26 + row * n
+        }
+        0xe000..=0xefff => 16,
+        _ => match instruction & 0xff {
+            0x07 | 0x15 | 0x18 => 10,
+            0x1e => 19,
+            0x29 => 20,
+            // The VIP converts to decimal by repeated subtraction.
+            0x33 => {
+                let digits = (vx / 100) + (vx / 10 % 10) + (vx % 10);
+                36 + 8 * digits as usize
+            }
+            0x55 | 0x65 => 14 + 14 * (x + 1),
+            _ => 10,
+        },
+    }
+}
@@ -4,11 +4,7 @@ pub fn get_hex_digits(n: &u16, d: u32, o: u32) -> usize {
 }
 
 pub fn is_bit_set(byte: &u8, n: u8) -> bool {
-    if byte & (1 << n) == 0 {
-        false
-    } else {
-        true
-    }
+    byte & (1 << n) != 0
 }
 
 pub fn get_bit(byte: &u8, n: u8) -> u8 {
@@ -18,3 +14,12 @@ pub fn get_bit(byte: &u8, n: u8) -> u8 {
         0
     }
 }
+
+// 64-bit FNV-1a, used to compare machine state between instances.
+pub fn hash_bytes(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+pub const HASH_SEED: u64 = 0xcbf29ce484222325;
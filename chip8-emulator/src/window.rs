@@ -1,15 +1,22 @@
-use minifb::{Error, Key, Scale, WindowOptions};
+use minifb::{Error, Key, KeyRepeat, Scale, WindowOptions};
 
+use crate::control::Hotkey;
 use crate::util::is_bit_set;
 
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
-const PX_OFF: u32 = 0x81c784;
-const PX_ON: u32 = 0x29302a;
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+pub const PX_OFF: u32 = 0x81c784;
+pub const PX_ON: u32 = 0x29302a;
 
+// Without a minifb window (headless mode) the display is still emulated but
+// nothing is shown and no keys are ever pressed.
 pub struct Window {
-    win: minifb::Window,
+    win: Option<minifb::Window>,
+    title: String,
+    px_on: u32,
+    px_off: u32,
     framebuffer: [u32; WIDTH * HEIGHT],
+    overlay: Vec<Option<u32>>,
 }
 
 impl Window {
@@ -30,14 +37,35 @@ impl Window {
         };
         win.limit_update_rate(Some(std::time::Duration::from_micros(2083)));
         Ok(Window {
-            win,
+            win: Some(win),
+            title: String::from(title),
+            px_on: PX_ON,
+            px_off: PX_OFF,
             framebuffer: [PX_OFF; WIDTH * HEIGHT],
+            overlay: Vec::new(),
         })
     }
 
+    pub fn headless() -> Window {
+        Window {
+            win: None,
+            title: String::new(),
+            px_on: PX_ON,
+            px_off: PX_OFF,
+            framebuffer: [PX_OFF; WIDTH * HEIGHT],
+            overlay: Vec::new(),
+        }
+    }
+
     pub fn handle_key_events(&self) -> [bool; 16] {
         let mut keys = [false; 16];
-        self.win.get_keys().iter().for_each(|k| {
+        let win = match &self.win {
+            Some(w) => w,
+            None => {
+                return keys;
+            }
+        };
+        win.get_keys().iter().for_each(|k| {
             match k {
                 Key::Key1 => keys[0x1] = true,
                 Key::Key2 => keys[0x2] = true,
@@ -61,34 +89,90 @@ impl Window {
         keys
     }
 
+    pub fn handle_hotkeys(&self) -> Vec<Hotkey> {
+        let mut hotkeys = Vec::new();
+        let win = match &self.win {
+            Some(w) => w,
+            None => {
+                return hotkeys;
+            }
+        };
+        win.get_keys_pressed(KeyRepeat::No).iter().for_each(|k| {
+            match k {
+                Key::P => hotkeys.push(Hotkey::Pause),
+                Key::N => hotkeys.push(Hotkey::FrameAdvance),
+                Key::Tab => hotkeys.push(Hotkey::FastForward),
+                Key::M => hotkeys.push(Hotkey::SlowMotion),
+                Key::Backspace => hotkeys.push(Hotkey::Reset),
+                _ => (),
+            };
+        });
+        hotkeys
+    }
+
+    pub fn set_status(&mut self, status: &str) {
+        if let Some(win) = &mut self.win {
+            win.set_title(&format!("{} [{}]", self.title, status));
+        }
+    }
+
     pub fn is_key_down(&self, key: Key) -> bool {
-        self.win.is_key_down(key)
+        match &self.win {
+            Some(w) => w.is_key_down(key),
+            None => false,
+        }
     }
 
     pub fn is_open(&self) -> bool {
-        self.win.is_open()
+        match &self.win {
+            Some(w) => w.is_open(),
+            None => true,
+        }
+    }
+
+    pub fn set_palette(&mut self, px_on: u32, px_off: u32) {
+        for p in self.framebuffer.iter_mut() {
+            *p = if *p == self.px_on { px_on } else { px_off };
+        }
+        self.px_on = px_on;
+        self.px_off = px_off;
+    }
+
+    pub fn pixels(&self) -> Vec<bool> {
+        self.framebuffer.iter().map(|p| *p == self.px_on).collect()
+    }
+
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
     }
 
     pub fn clear_screen(&mut self) {
         for j in 0..self.framebuffer.len() {
-            self.framebuffer[j] = PX_OFF;
+            self.framebuffer[j] = self.px_off;
         }
     }
 
-    pub fn draw(&mut self, bytes: &Vec<u8>, init_x: u8, init_y: u8) -> u8 {
+    pub fn draw(&mut self, bytes: &[u8], init_x: u8, init_y: u8, clip: bool) -> u8 {
         let mut collision: u8 = 0;
         for (k, b) in bytes.iter().enumerate() {
             for j in 0..8 {
-                let x = (init_x as usize + j) % WIDTH;
-                let y = (init_y as usize + k) % HEIGHT;
+                let mut x = (init_x as usize % WIDTH) + j;
+                let mut y = (init_y as usize % HEIGHT) + k;
+                if x >= WIDTH || y >= HEIGHT {
+                    if clip {
+                        continue;
+                    }
+                    x %= WIDTH;
+                    y %= HEIGHT;
+                }
                 let coord = (y * WIDTH) + x;
-                let is_old_set = self.framebuffer[coord] == PX_ON;
+                let is_old_set = self.framebuffer[coord] == self.px_on;
                 self.framebuffer[coord] = if is_bit_set(b, (8 - j - 1) as u8) {
                     if is_old_set {
                         collision = 1;
-                        PX_OFF
+                        self.px_off
                     } else {
-                        PX_ON
+                        self.px_on
                     }
                 } else {
                     self.framebuffer[coord]
@@ -98,9 +182,29 @@ impl Window {
         collision
     }
 
+    // Pixels drawn on top of the CHIP-8 display, e.g. by a script's HUD.
+    pub fn set_overlay(&mut self, overlay: Vec<Option<u32>>) {
+        self.overlay = overlay;
+    }
+
     pub fn refresh(&mut self) {
-        self.win
-            .update_with_buffer(&self.framebuffer, WIDTH, HEIGHT)
-            .unwrap();
+        let win = match &mut self.win {
+            Some(w) => w,
+            None => {
+                return;
+            }
+        };
+        if self.overlay.iter().all(|p| p.is_none()) {
+            win.update_with_buffer(&self.framebuffer, WIDTH, HEIGHT)
+                .unwrap();
+            return;
+        }
+        let mut buffer = self.framebuffer;
+        for (j, p) in self.overlay.iter().enumerate() {
+            if let Some(color) = p {
+                buffer[j] = *color;
+            }
+        }
+        win.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
     }
 }
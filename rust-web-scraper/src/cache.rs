@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::header::{HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use url::Url;
+
+use crate::error::Error;
+use crate::http::Http;
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    // How long a page is served without asking the site again. After that
+    // it is revalidated, which is cheap if the site supports it.
+    pub ttl_secs: u64,
+    // Pages kept at most; the least recently used go first.
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig { ttl_secs: 60, max_entries: 1000 }
+    }
+}
+
+// Locked while the page is being fetched; empty until it first has been.
+type Slot = Arc<tokio::sync::Mutex<Option<Entry>>>;
+
+type Key = (String, String);
+
+struct Entry {
+    body: String,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    fetched: Instant,
+}
+
+// Pages by source and URL. Each key has its own lock, held while the page is
+// fetched, so concurrent requests for it wait for that one fetch instead of
+// making their own.
+pub struct Cache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<Key, (Slot, Instant)>>,
+}
+
+// What the cache did for the pages of one API request.
+#[derive(Default)]
+pub struct Tally {
+    pub hits: usize,
+    pub revalidated: usize,
+    pub misses: usize,
+}
+
+impl Tally {
+    // HIT if nothing had to be downloaded again.
+    pub fn status(&self) -> &'static str {
+        if self.misses > 0 {
+            "MISS"
+        } else if self.revalidated > 0 {
+            "REVALIDATED"
+        } else {
+            "HIT"
+        }
+    }
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig) -> Cache {
+        Cache { ttl: Duration::from_secs(config.ttl_secs), max_entries: config.max_entries.max(1), entries: Mutex::new(HashMap::new()) }
+    }
+
+    // The page's slot, marked as just used. The least recently used pages
+    // that nobody is fetching are dropped until the cache is back within
+    // max_entries; pages being fetched can push it over for a while.
+    fn slot(&self, key: Key) -> Slot {
+        let mut entries = self.entries.lock().unwrap();
+        let adding = usize::from(!entries.contains_key(&key));
+        while entries.len() + adding > self.max_entries {
+            let oldest = entries
+                .iter()
+                .filter(|(k, (slot, _))| **k != key && Arc::strong_count(slot) == 1)
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => break,
+            };
+        }
+        let (slot, used) = entries.entry(key).or_insert_with(|| (Slot::default(), Instant::now()));
+        *used = Instant::now();
+        slot.clone()
+    }
+
+    pub async fn fetch(&self, http: &Http, source: &str, url: &Url, tally: &mut Tally) -> Result<String, Error> {
+        let slot = self.slot((source.to_string(), url.to_string()));
+        let mut entry = slot.lock().await;
+
+        let mut headers = HeaderMap::new();
+        if let Some(cached) = entry.as_mut() {
+            if cached.fetched.elapsed() < self.ttl {
+                tally.hits += 1;
+                return Ok(cached.body.clone());
+            }
+            if let Some(etag) = &cached.etag {
+                headers.insert(IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(date) = &cached.last_modified {
+                headers.insert(IF_MODIFIED_SINCE, date.clone());
+            }
+        }
+
+        let fetched = http.request(url, &headers).await?;
+        if fetched.status == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = entry.as_mut() {
+                cached.fetched = Instant::now();
+                tally.revalidated += 1;
+                return Ok(cached.body.clone());
+            }
+        }
+        tally.misses += 1;
+        if !fetched.status.is_success() {
+            return Err(Error::Upstream { url: url.to_string(), status: fetched.status.as_u16() });
+        }
+        *entry = Some(Entry {
+            body: fetched.body.clone(),
+            etag: fetched.headers.get(ETAG).cloned(),
+            last_modified: fetched.headers.get(LAST_MODIFIED).cloned(),
+            fetched: Instant::now(),
+        });
+        Ok(fetched.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(url: &str) -> Key {
+        ("source".to_string(), url.to_string())
+    }
+
+    #[test]
+    fn least_recently_used_page_is_dropped() {
+        let cache = Cache::new(&CacheConfig { ttl_secs: 60, max_entries: 2 });
+        cache.slot(key("a"));
+        cache.slot(key("b"));
+        cache.slot(key("a"));
+        cache.slot(key("c"));
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains_key(&key("a")));
+        assert!(!entries.contains_key(&key("b")));
+    }
+
+    #[test]
+    fn pages_being_fetched_are_kept() {
+        let cache = Cache::new(&CacheConfig { ttl_secs: 60, max_entries: 1 });
+        let fetching = cache.slot(key("a"));
+        cache.slot(key("b"));
+        assert!(cache.entries.lock().unwrap().contains_key(&key("a")));
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+
+        // Once it is fetched, the cap holds again.
+        drop(fetching);
+        cache.slot(key("c"));
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key(&key("c")));
+    }
+
+    #[test]
+    fn using_a_cached_page_restores_the_cap() {
+        let cache = Cache::new(&CacheConfig { ttl_secs: 60, max_entries: 1 });
+        let fetching = cache.slot(key("a"));
+        cache.slot(key("b"));
+        drop(fetching);
+        cache.slot(key("b"));
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key(&key("b")));
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use regex::Regex;
+use serde::Deserialize;
+use url::Url;
+
+use crate::cache::CacheConfig;
+use crate::error::Error;
+use crate::extractors::Extraction;
+use crate::http::HttpConfig;
+use crate::jobs::Schedule;
+use crate::metadata;
+use crate::notify::NotifyConfig;
+use crate::search::SearchConfig;
+use crate::store::StoreConfig;
+use crate::schema::{parse_selector, FieldSpec};
+
+const DEFAULT_BIND: &str = "127.0.0.1:8080";
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    #[serde(default, rename = "source")]
+    pub sources: Vec<Source>,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub store: StoreConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    #[serde(default)]
+    pub search: SearchConfig,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Source {
+    pub name: String,
+    pub url: String,
+    // Without an item selector, the extractor written for the site is used.
+    pub item: Option<String>,
+    #[serde(default)]
+    pub fields: BTreeMap<String, FieldSpec>,
+    // The field that identifies an item, used to drop duplicates.
+    #[serde(default = "default_key")]
+    pub key: String,
+    // Follow links from the source URL instead of scraping only that page.
+    pub crawl: Option<Crawl>,
+    // How often to scrape: a period like "15m", or a cron expression with
+    // seconds, e.g. "0 */10 * * * *".
+    pub every: Option<String>,
+    pub cron: Option<String>,
+    // Where to read descriptions, images, authors and dates from, if at all.
+    pub metadata: Option<metadata::Mode>,
+    // Fetch each article's page and keep its main text.
+    #[serde(default)]
+    pub content: bool,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Crawl {
+    // Where to start; the source URL if empty.
+    #[serde(default)]
+    pub seeds: Vec<String>,
+    // Regexes a link's URL must match one of, and must match none of.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+    #[serde(default = "default_max_pages")]
+    pub max_pages: usize,
+    #[serde(default = "default_true")]
+    pub same_domain: bool,
+    #[serde(default = "default_true")]
+    pub robots: bool,
+    // Milliseconds between requests to the same host, unless robots.txt
+    // asks for longer.
+    #[serde(default)]
+    pub delay_ms: u64,
+    // Selector for a "next page" link, on top of rel=next.
+    pub next: Option<String>,
+}
+
+fn default_bind() -> String {
+    DEFAULT_BIND.to_string()
+}
+
+pub fn default_key() -> String {
+    "link".to_string()
+}
+
+fn default_max_depth() -> usize {
+    1
+}
+
+fn default_max_pages() -> usize {
+    20
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Config {
+    pub fn source(&self, name: &str) -> Option<&Source> {
+        self.sources.iter().find(|s| s.name == name)
+    }
+}
+
+impl Source {
+    // Parses the URL, selectors and regexes without fetching anything.
+    pub fn check(&self) -> Result<(), Error> {
+        Url::parse(&self.url).map_err(|e| Error::Parse(format!("invalid url {:?}: {}", self.url, e)))?;
+        Extraction::for_source(self)?;
+        Schedule::parse(self)?;
+        if let Some(crawl) = &self.crawl {
+            for seed in &crawl.seeds {
+                Url::parse(seed).map_err(|e| Error::Parse(format!("invalid seed {:?}: {}", seed, e)))?;
+            }
+            for pattern in crawl.include.iter().chain(&crawl.exclude) {
+                Regex::new(pattern)?;
+            }
+            if let Some(next) = &crawl.next {
+                parse_selector(next)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sources are checked here so that a typo fails at startup rather than on the
+// first request.
+pub fn load(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let config: Config = toml::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    for (i, source) in config.sources.iter().enumerate() {
+        if config.sources[..i].iter().any(|s| s.name == source.name) {
+            return Err(format!("duplicate source name: {}", source.name).into());
+        }
+        source.check().map_err(|e| format!("source {}: {}", source.name, e))?;
+    }
+    for webhook in &config.notify.webhooks {
+        Url::parse(&webhook.url).map_err(|e| format!("invalid webhook url {:?}: {}", webhook.url, e))?;
+        if let Some(name) = webhook.sources.iter().find(|name| config.source(name).is_none()) {
+            return Err(format!("webhook {}: no source named {}", webhook.url, name).into());
+        }
+    }
+    Ok(config)
+}
@@ -0,0 +1,196 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use scraper::{Html, Selector};
+use url::Url;
+
+use crate::cache::{Cache, Tally};
+use crate::config::{Crawl, Source};
+use crate::error::Error;
+use crate::extractors::Extraction;
+use crate::http::Http;
+use crate::models::Article;
+use crate::robots::Robots;
+use crate::schema::parse_selector;
+use crate::scraper::extract;
+use crate::urls;
+
+const NEXT_SELECTOR: &str = "a[rel~=next][href], link[rel~=next][href]";
+
+struct Crawler<'a> {
+    http: &'a Http,
+    source: &'a Source,
+    crawl: &'a Crawl,
+    extraction: Extraction,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    next: Vec<Selector>,
+    domains: HashSet<String>,
+    robots: HashMap<String, Robots>,
+    last_fetch: HashMap<String, Instant>,
+}
+
+// The links one page yielded besides its items.
+struct Page {
+    next: Vec<Url>,
+    links: Vec<Url>,
+}
+
+// Breadth-first from the seeds. "Next page" links are followed at the same
+// depth, so a paginated listing is scraped to the end however deep it goes,
+// up to max_pages.
+pub async fn crawl(http: &Http, cache: &Cache, source: &Source, crawl: &Crawl, tally: &mut Tally) -> Result<Vec<Article>, Error> {
+    let seeds: Vec<Url> = if crawl.seeds.is_empty() {
+        vec![urls::normalize(Url::parse(&source.url)?)]
+    } else {
+        crawl.seeds.iter().map(|s| Url::parse(s).map(urls::normalize)).collect::<Result<_, _>>()?
+    };
+
+    let mut next = vec![parse_selector(NEXT_SELECTOR)?];
+    if let Some(s) = &crawl.next {
+        next.push(parse_selector(s)?);
+    }
+    let mut crawler = Crawler {
+        http,
+        source,
+        crawl,
+        extraction: Extraction::for_source(source)?,
+        include: crawl.include.iter().map(|p| Regex::new(p)).collect::<Result<_, _>>()?,
+        exclude: crawl.exclude.iter().map(|p| Regex::new(p)).collect::<Result<_, _>>()?,
+        next,
+        domains: seeds.iter().filter_map(|s| s.host_str().map(|h| h.to_string())).collect(),
+        robots: HashMap::new(),
+        last_fetch: HashMap::new(),
+    };
+
+    let mut queue: VecDeque<(Url, usize)> = seeds.into_iter().map(|s| (s, 0)).collect();
+    let mut visited = HashSet::new();
+    let mut seen = HashSet::new();
+    let mut articles = Vec::new();
+    let mut pages = 0;
+
+    while let Some((url, depth)) = queue.pop_front() {
+        if pages >= crawl.max_pages {
+            break;
+        }
+        if !visited.insert(url.to_string()) || !crawler.allowed(&url).await {
+            continue;
+        }
+        crawler.wait(&url).await;
+        let body = match cache.fetch(http, &source.name, &url, tally).await {
+            Ok(body) => body,
+            // A dead link deep in the site shouldn't lose everything else.
+            Err(e) if pages > 0 => {
+                eprintln!("crawl {}: {}: {}", source.name, url, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        pages += 1;
+
+        let page = match crawler.process(&body, &url, &mut visited, &mut seen, &mut articles) {
+            Some(page) => page,
+            None => continue,
+        };
+        for link in page.next.into_iter().rev() {
+            if crawler.on_site(&link) {
+                queue.push_front((link, depth));
+            }
+        }
+        if depth < crawl.max_depth {
+            for link in page.links {
+                if crawler.follow(&link) {
+                    queue.push_back((link, depth + 1));
+                }
+            }
+        }
+    }
+    if articles.is_empty() {
+        return Err(Error::NoItems(source.url.clone()));
+    }
+    Ok(articles)
+}
+
+impl Crawler<'_> {
+    // Returns None if the page's canonical URL was already visited under
+    // another name.
+    fn process(&self, body: &str, url: &Url, visited: &mut HashSet<String>, seen: &mut HashSet<String>, articles: &mut Vec<Article>) -> Option<Page> {
+        let document = Html::parse_document(body);
+        if let Some(canonical) = urls::canonical(&document, url) {
+            if &canonical != url && !visited.insert(canonical.to_string()) {
+                return None;
+            }
+        }
+        articles.extend(extract(&document, url, &self.extraction, self.source, seen));
+
+        let base = urls::base(&document, url);
+        let hrefs = |selector: &Selector| -> Vec<Url> {
+            document
+                .select(selector)
+                .filter_map(|e| e.value().attr("href"))
+                .filter_map(|href| urls::resolve(&base, href))
+                .filter(|u| u.scheme() == "http" || u.scheme() == "https")
+                .collect()
+        };
+        Some(Page {
+            next: self.next.iter().flat_map(&hrefs).collect(),
+            links: hrefs(&parse_selector("a[href]").unwrap()),
+        })
+    }
+
+    fn on_site(&self, url: &Url) -> bool {
+        !self.crawl.same_domain || url.host_str().map(|h| self.domains.contains(h)).unwrap_or(false)
+    }
+
+    fn follow(&self, url: &Url) -> bool {
+        let s = url.as_str();
+        self.on_site(url)
+            && (self.include.is_empty() || self.include.iter().any(|r| r.is_match(s)))
+            && !self.exclude.iter().any(|r| r.is_match(s))
+    }
+
+    // robots.txt is fetched once per host; if it can't be had, everything is
+    // allowed.
+    async fn allowed(&mut self, url: &Url) -> bool {
+        if !self.crawl.robots {
+            return true;
+        }
+        let origin = url.origin().ascii_serialization();
+        if !self.robots.contains_key(&origin) {
+            let robots = match url.join("/robots.txt") {
+                Ok(robots_url) => {
+                    self.wait(url).await;
+                    match self.http.get(&robots_url).await {
+                        Ok(fetched) if fetched.status.is_success() => Robots::parse(&fetched.body, self.http.user_agent()),
+                        _ => Robots::default(),
+                    }
+                }
+                Err(_) => Robots::default(),
+            };
+            self.robots.insert(origin.clone(), robots);
+        }
+        let path = match url.query() {
+            Some(q) => format!("{}?{}", url.path(), q),
+            None => url.path().to_string(),
+        };
+        self.robots[&origin].allowed(&path)
+    }
+
+    // Spaces out requests to the same host by the configured delay or the
+    // robots.txt Crawl-delay, whichever is longer.
+    async fn wait(&mut self, url: &Url) {
+        let origin = url.origin().ascii_serialization();
+        let mut delay = Duration::from_millis(self.crawl.delay_ms);
+        if let Some(d) = self.robots.get(&origin).and_then(|r| r.crawl_delay) {
+            delay = delay.max(d);
+        }
+        if let Some(last) = self.last_fetch.get(&origin) {
+            let elapsed = last.elapsed();
+            if elapsed < delay {
+                tokio::time::sleep(delay - elapsed).await;
+            }
+        }
+        self.last_fetch.insert(origin, Instant::now());
+    }
+}
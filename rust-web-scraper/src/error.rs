@@ -0,0 +1,166 @@
+use std::fmt;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
+
+// Everything that can go wrong serving a request, by what the client can do
+// about it.
+#[derive(Debug)]
+pub enum Error {
+    // The site took too long to connect or answer.
+    Timeout(String),
+    // The host couldn't be resolved or reached.
+    Connect(String),
+    // Any other failure while talking to the site.
+    Fetch(String),
+    // The site answered with an error status.
+    Upstream { url: String, status: u16 },
+    Selector(String),
+    // A URL, regex, date or schedule that doesn't parse.
+    Parse(String),
+    // The item selector matched nothing, usually because the site changed.
+    NoItems(String),
+    // Nothing on the page looks like an article's text.
+    NoContent(String),
+    UnknownSource(String),
+    BadRequest(String),
+    Store(String),
+    Internal(String),
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Timeout(_) => "fetch_timeout",
+            Error::Connect(_) => "connect_failed",
+            Error::Fetch(_) => "fetch_failed",
+            Error::Upstream { .. } => "upstream_status",
+            Error::Selector(_) => "invalid_selector",
+            Error::Parse(_) => "parse_error",
+            Error::NoItems(_) => "no_items",
+            Error::NoContent(_) => "no_content",
+            Error::UnknownSource(_) => "unknown_source",
+            Error::BadRequest(_) => "bad_request",
+            Error::Store(_) => "store_error",
+            Error::Internal(_) => "internal_error",
+        }
+    }
+
+    // Puts e.g. the field name in front of the message, keeping the kind.
+    pub fn context(self, prefix: impl fmt::Display) -> Error {
+        let wrap = |message: String| format!("{}: {}", prefix, message);
+        match self {
+            Error::Timeout(m) => Error::Timeout(wrap(m)),
+            Error::Connect(m) => Error::Connect(wrap(m)),
+            Error::Fetch(m) => Error::Fetch(wrap(m)),
+            Error::Selector(m) => Error::Selector(wrap(m)),
+            Error::Parse(m) => Error::Parse(wrap(m)),
+            Error::NoItems(m) => Error::NoItems(wrap(m)),
+            Error::NoContent(m) => Error::NoContent(wrap(m)),
+            Error::UnknownSource(m) => Error::UnknownSource(wrap(m)),
+            Error::BadRequest(m) => Error::BadRequest(wrap(m)),
+            Error::Store(m) => Error::Store(wrap(m)),
+            Error::Internal(m) => Error::Internal(wrap(m)),
+            upstream @ Error::Upstream { .. } => upstream,
+        }
+    }
+
+    // Sorts out what reqwest says went wrong.
+    pub fn from_reqwest(url: &str, e: reqwest::Error) -> Error {
+        if e.is_timeout() {
+            Error::Timeout(format!("{}: timed out", url))
+        } else if e.is_connect() {
+            Error::Connect(e.to_string())
+        } else {
+            Error::Fetch(e.to_string())
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Upstream { url, status } => write!(f, "{} returned {}", url, status),
+            Error::UnknownSource(name) => write!(f, "no source named {}", name),
+            Error::NoItems(url) => write!(f, "{}: the item selector matched nothing", url),
+            Error::NoContent(url) => write!(f, "{}: found no readable text", url),
+            Error::Timeout(m)
+            | Error::Connect(m)
+            | Error::Fetch(m)
+            | Error::Selector(m)
+            | Error::Parse(m)
+            | Error::BadRequest(m)
+            | Error::Store(m)
+            | Error::Internal(m) => f.write_str(m),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<url::ParseError> for Error {
+    fn from(e: url::ParseError) -> Error {
+        Error::Parse(format!("invalid url: {}", e))
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Error {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Store(e.to_string())
+    }
+}
+
+// An error on its way to the client, with the source it concerns if any.
+#[derive(Debug)]
+pub struct ApiError {
+    pub error: Error,
+    pub source: Option<String>,
+}
+
+impl ApiError {
+    pub fn for_source(source: &str) -> impl Fn(Error) -> ApiError + '_ {
+        move |error| ApiError { error, source: Some(source.to_string()) }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> ApiError {
+        ApiError { error, source: None }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self.error {
+            Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Connect(_) | Error::Fetch(_) | Error::Upstream { .. } => StatusCode::BAD_GATEWAY,
+            Error::Selector(_) | Error::Parse(_) | Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::NoItems(_) | Error::NoContent(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::UnknownSource(_) => StatusCode::NOT_FOUND,
+            Error::Store(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "error": {
+                "code": self.error.code(),
+                "message": self.error.to_string(),
+                "source": self.source,
+            }
+        }))
+    }
+}
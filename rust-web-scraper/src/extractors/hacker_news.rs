@@ -0,0 +1,189 @@
+use std::sync::OnceLock;
+
+use scraper::{ElementRef, Html, Selector};
+use serde_json::{json, Map, Value};
+use url::Url;
+
+use super::Extractor;
+use crate::models::Article;
+use crate::schema::{parse_date, parse_selector};
+use crate::urls;
+
+// Hacker News listings (the front page, /newest, /ask, /show and so on). Each
+// story is split over two table rows, the title and the line under it, which
+// no single item selector can match.
+pub struct HackerNews;
+
+const HOST: &str = "news.ycombinator.com";
+
+fn text(element: ElementRef) -> String {
+    element.text().collect::<String>().split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+struct Selectors {
+    story: Selector,
+    title: Selector,
+    site: Selector,
+    subtext: Selector,
+    score: Selector,
+    user: Selector,
+    age: Selector,
+    link: Selector,
+}
+
+// Parsed on first use and kept for every page after.
+fn selectors() -> &'static Selectors {
+    static SELECTORS: OnceLock<Selectors> = OnceLock::new();
+    SELECTORS.get_or_init(|| {
+        let parse = |s| parse_selector(s).unwrap();
+        Selectors {
+            story: parse("tr.athing"),
+            title: parse(".titleline > a"),
+            site: parse(".sitestr"),
+            subtext: parse(".subtext"),
+            score: parse(".score"),
+            user: parse(".hnuser"),
+            age: parse(".age"),
+            link: parse("a"),
+        }
+    })
+}
+
+fn select<'a>(element: ElementRef<'a>, selector: &Selector) -> Option<ElementRef<'a>> {
+    element.select(selector).next()
+}
+
+// The number at the start of "123 points" or "45 comments".
+fn count(s: &str) -> Option<u64> {
+    s.split_whitespace().next()?.parse().ok()
+}
+
+impl Extractor for HackerNews {
+    fn matches(&self, url: &Url) -> bool {
+        url.host_str() == Some(HOST) && !url.path().starts_with("/item") && !url.path().starts_with("/user")
+    }
+
+    fn extract(&self, document: &Html, page: &Url) -> Vec<Article> {
+        let base = urls::base(document, page);
+        let selectors = selectors();
+        document
+            .select(&selectors.story)
+            .filter_map(|story| {
+                let title = select(story, &selectors.title)?;
+                let id = story.value().id()?;
+                let discussion = base.join(&format!("item?id={}", id)).ok()?;
+                let link = title.value().attr("href").and_then(|href| urls::resolve(&base, href)).unwrap_or_else(|| discussion.clone());
+                // Job ads have no score, author or comments.
+                let subtext = story.next_siblings().find_map(ElementRef::wrap).and_then(|row| select(row, &selectors.subtext));
+                let field = |selector: &Selector| subtext.and_then(|s| select(s, selector));
+                let comments = subtext.and_then(|s| {
+                    s.select(&selectors.link).map(text).find(|t| t.ends_with("comments") || t.ends_with("comment") || t == "discuss")
+                });
+
+                let mut fields = Map::new();
+                fields.insert("id".to_string(), json!(id));
+                fields.insert("title".to_string(), json!(text(title)));
+                fields.insert("link".to_string(), json!(link.as_str()));
+                fields.insert("site".to_string(), select(story, &selectors.site).map(text).map(Value::from).unwrap_or(Value::Null));
+                fields.insert("score".to_string(), field(&selectors.score).and_then(|s| count(&text(s))).map(Value::from).unwrap_or(Value::Null));
+                fields.insert("author".to_string(), field(&selectors.user).map(text).map(Value::from).unwrap_or(Value::Null));
+                fields.insert(
+                    "published_at".to_string(),
+                    field(&selectors.age)
+                        .and_then(|a| a.value().attr("title"))
+                        .and_then(|t| t.split_whitespace().next())
+                        .and_then(parse_date)
+                        .map(Value::from)
+                        .unwrap_or(Value::Null),
+                );
+                fields.insert("comments".to_string(), comments.map(|c| Value::from(count(&c).unwrap_or(0))).unwrap_or(Value::Null));
+                fields.insert("discussion".to_string(), json!(discussion.as_str()));
+                Some(Article { fields, ..Article::default() })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<html><body><table>
+        <tr class="athing" id="101">
+          <td class="title"><span class="titleline"><a href="https://example.com/post?utm_source=hn">A  post</a>
+            <span class="sitebit comhead">(<a href="from?site=example.com"><span class="sitestr">example.com</span></a>)</span></span></td>
+        </tr>
+        <tr><td class="subtext"><span class="subline">
+          <span class="score" id="score_101">123 points</span> by <a href="user?id=alice" class="hnuser">alice</a>
+          <span class="age" title="2024-05-06T07:08:09 1714979289"><a href="item?id=101">2 hours ago</a></span> |
+          <a href="hide?id=101">hide</a> | <a href="item?id=101">45&nbsp;comments</a>
+        </span></td></tr>
+        <tr class="spacer"></tr>
+        <tr class="athing" id="102">
+          <td class="title"><span class="titleline"><a href="item?id=102">Ask HN: Anything?</a></span></td>
+        </tr>
+        <tr><td class="subtext"><span class="subline">
+          <span class="score" id="score_102">1 point</span> by <a href="user?id=bob" class="hnuser">bob</a>
+          <span class="age" title="2024-05-06T08:00:00 1714982400"><a href="item?id=102">1 hour ago</a></span> |
+          <a href="item?id=102">discuss</a>
+        </span></td></tr>
+        <tr class="athing" id="103">
+          <td class="title"><span class="titleline"><a href="https://jobs.example.com/">Example is hiring</a></span></td>
+        </tr>
+        <tr><td class="subtext"><span class="age" title="2024-05-06T09:00:00 1714986000"><a href="item?id=103">now</a></span></td></tr>
+    </table></body></html>"#;
+
+    fn stories() -> Vec<Map<String, Value>> {
+        let page = Url::parse("https://news.ycombinator.com/news").unwrap();
+        HackerNews.extract(&Html::parse_document(PAGE), &page).into_iter().map(|a| a.fields).collect()
+    }
+
+    #[test]
+    fn stories_are_read_from_both_rows() {
+        let stories = stories();
+        assert_eq!(stories.len(), 3);
+        assert_eq!(
+            Value::Object(stories[0].clone()),
+            json!({
+                "id": "101",
+                "title": "A post",
+                "link": "https://example.com/post",
+                "site": "example.com",
+                "score": 123,
+                "author": "alice",
+                "published_at": "2024-05-06T07:08:09+00:00",
+                "comments": 45,
+                "discussion": "https://news.ycombinator.com/item?id=101",
+            })
+        );
+    }
+
+    #[test]
+    fn ask_posts_link_to_their_discussion() {
+        let ask = &stories()[1];
+        assert_eq!(ask["link"], "https://news.ycombinator.com/item?id=102");
+        assert_eq!(ask["site"], Value::Null);
+        assert_eq!(ask["comments"], 0);
+    }
+
+    #[test]
+    fn job_ads_have_no_score_author_or_comments() {
+        let job = &stories()[2];
+        assert_eq!(job["title"], "Example is hiring");
+        assert_eq!(job["score"], Value::Null);
+        assert_eq!(job["author"], Value::Null);
+        assert_eq!(job["comments"], Value::Null);
+        assert_eq!(job["published_at"], "2024-05-06T09:00:00+00:00");
+    }
+
+    #[test]
+    fn only_listings_match() {
+        let matches = |url: &str| HackerNews.matches(&Url::parse(url).unwrap());
+        assert!(matches("https://news.ycombinator.com/"));
+        assert!(matches("https://news.ycombinator.com/newest"));
+        assert!(matches("https://news.ycombinator.com/ask?p=2"));
+        assert!(!matches("https://news.ycombinator.com/item?id=101"));
+        assert!(!matches("https://news.ycombinator.com/user?id=alice"));
+        assert!(!matches("https://example.com/"));
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+
+use scraper::{Html, Selector};
+use url::Url;
+
+use crate::config::Source;
+use crate::error::Error;
+use crate::models::{Article, RESERVED};
+use crate::schema::{parse_selector, FieldSpec, Schema};
+use crate::urls;
+
+mod hacker_news;
+
+// Reads the items off one kind of page.
+pub trait Extractor: Sync {
+    fn matches(&self, url: &Url) -> bool;
+    fn extract(&self, document: &Html, page: &Url) -> Vec<Article>;
+}
+
+// Hand-written extractors for sites whose markup the selectors can't
+// describe, tried in order. Each lives in its own module here.
+static REGISTRY: &[&dyn Extractor] = &[&hacker_news::HackerNews];
+
+pub fn find(url: &Url) -> Option<&'static dyn Extractor> {
+    REGISTRY.iter().copied().find(|e| e.matches(url))
+}
+
+// The generic extractor: an item selector and the fields to read from each
+// item.
+pub struct Selectors {
+    item: Selector,
+    schema: Schema,
+}
+
+impl Selectors {
+    pub fn compile(item: &str, fields: &BTreeMap<String, FieldSpec>) -> Result<Selectors, Error> {
+        if let Some(name) = fields.keys().find(|name| RESERVED.contains(&name.as_str())) {
+            return Err(Error::Parse(format!("field {}: the name is taken by the article's own {}", name, name)));
+        }
+        Ok(Selectors { item: parse_selector(item)?, schema: Schema::compile(fields)? })
+    }
+}
+
+impl Extractor for Selectors {
+    fn matches(&self, _: &Url) -> bool {
+        true
+    }
+
+    fn extract(&self, document: &Html, page: &Url) -> Vec<Article> {
+        let base = urls::base(document, page);
+        document
+            .select(&self.item)
+            .filter_map(|item| self.schema.extract(item, &base))
+            .map(|fields| Article { fields, ..Article::default() })
+            .collect()
+    }
+}
+
+// How a source's pages are read: with its own selectors if it gives an item
+// selector, otherwise by whichever registered extractor matches each page.
+// Extractors decide their own fields, so fields without an item selector are
+// an error rather than silently ignored.
+pub enum Extraction {
+    Selectors(Selectors),
+    Registry,
+}
+
+impl Extraction {
+    pub fn for_source(source: &Source) -> Result<Extraction, Error> {
+        match &source.item {
+            Some(item) => Ok(Extraction::Selectors(Selectors::compile(item, &source.fields)?)),
+            None if !source.fields.is_empty() => Err(Error::BadRequest("fields need an item selector; a site's extractor picks its own".to_string())),
+            None if find(&Url::parse(&source.url)?).is_some() => Ok(Extraction::Registry),
+            None => Err(Error::BadRequest(format!("no extractor handles {}, so an item selector is needed", source.url))),
+        }
+    }
+
+    pub fn extractor(&self, page: &Url) -> Option<&dyn Extractor> {
+        match self {
+            Extraction::Selectors(selectors) => Some(selectors),
+            Extraction::Registry => find(page),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(toml: &str) -> Source {
+        toml::from_str(&format!("name = \"test\"\n{}", toml)).unwrap()
+    }
+
+    #[test]
+    fn sources_without_an_item_selector_use_the_registry() {
+        let hn = source("url = \"https://news.ycombinator.com/newest\"");
+        assert!(matches!(Extraction::for_source(&hn), Ok(Extraction::Registry)));
+
+        let unknown = source("url = \"https://example.com/\"");
+        assert!(matches!(Extraction::for_source(&unknown), Err(Error::BadRequest(_))));
+
+        let with_item = source("url = \"https://news.ycombinator.com/\"\nitem = \".athing\"\nfields = { title = \".titleline\" }");
+        assert!(matches!(Extraction::for_source(&with_item), Ok(Extraction::Selectors(_))));
+    }
+
+    #[test]
+    fn fields_without_an_item_selector_are_rejected() {
+        let hn = source("url = \"https://news.ycombinator.com/\"\nfields = { title = \".titleline\" }");
+        assert!(matches!(Extraction::for_source(&hn), Err(Error::BadRequest(_))));
+    }
+}
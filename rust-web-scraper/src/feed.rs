@@ -0,0 +1,150 @@
+use atom_syndication as atom;
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use serde_json::Value;
+
+use crate::config::Source;
+use crate::models::StoredArticle;
+
+// What a feed entry needs, taken from the article fields with the usual
+// names. Anything missing falls back to what the store knows, never to the
+// current time, so an entry reads the same every time the feed is fetched.
+struct Entry {
+    id: String,
+    title: String,
+    link: Option<String>,
+    summary: Option<String>,
+    content: Option<String>,
+    author: Option<String>,
+    date: DateTime<FixedOffset>,
+}
+
+fn text(article: &StoredArticle, names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| match article.article.fields.get(*name) {
+        Some(Value::String(s)) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Some(Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    })
+}
+
+fn date(s: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t);
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().fixed_offset())
+}
+
+fn entry(source: &Source, article: &StoredArticle) -> Entry {
+    let link = text(article, &["link", "url"]);
+    let meta = &article.article.metadata;
+    Entry {
+        id: text(article, &[&source.key]).or_else(|| link.clone()).unwrap_or_else(|| format!("urn:sha256:{}", article.hash)),
+        title: text(article, &["title", "name"]).or_else(|| link.clone()).unwrap_or_else(|| "(untitled)".to_string()),
+        link,
+        summary: text(article, &["description", "summary"]).or_else(|| meta.description.clone()),
+        content: article.article.content.as_ref().map(|c| c.html.clone()),
+        author: text(article, &["author"]).or_else(|| meta.author.clone()),
+        date: text(article, &["published_at", "date"]).or_else(|| meta.published_at.clone())
+            .and_then(|d| date(&d))
+            .or_else(|| date(&article.first_seen))
+            .or_else(|| date(&article.last_seen))
+            .unwrap_or(DateTime::UNIX_EPOCH.fixed_offset()),
+    }
+}
+
+pub fn rss(source: &Source, articles: &[StoredArticle]) -> String {
+    let items = articles
+        .iter()
+        .map(|article| {
+            let entry = entry(source, article);
+            rss::Item {
+                guid: Some(rss::Guid { permalink: entry.link.as_deref() == Some(entry.id.as_str()), value: entry.id }),
+                title: Some(entry.title),
+                link: entry.link,
+                description: entry.summary,
+                content: entry.content,
+                author: entry.author,
+                pub_date: Some(entry.date.to_rfc2822()),
+                ..Default::default()
+            }
+        })
+        .collect();
+    let channel = rss::Channel {
+        title: source.name.clone(),
+        link: source.url.clone(),
+        description: format!("Articles scraped from {}", source.url),
+        items,
+        ..Default::default()
+    };
+    channel.to_string()
+}
+
+pub fn atom(source: &Source, articles: &[StoredArticle]) -> String {
+    let entries: Vec<atom::Entry> = articles
+        .iter()
+        .map(|article| {
+            let entry = entry(source, article);
+            atom::Entry {
+                id: entry.id,
+                title: atom::Text::plain(entry.title),
+                updated: entry.date,
+                published: Some(entry.date),
+                links: entry.link.map(|href| vec![atom::Link { href, ..Default::default() }]).unwrap_or_default(),
+                summary: entry.summary.map(atom::Text::plain),
+                content: entry.content.map(|html| atom::Content { value: Some(html), content_type: Some("html".to_string()), ..Default::default() }),
+                authors: entry.author.map(|name| vec![atom::Person { name, ..Default::default() }]).unwrap_or_default(),
+                ..Default::default()
+            }
+        })
+        .collect();
+    let feed = atom::Feed {
+        id: source.url.clone(),
+        title: atom::Text::plain(source.name.clone()),
+        updated: entries.iter().map(|e| e.updated).max().unwrap_or_else(|| Utc::now().fixed_offset()),
+        links: vec![atom::Link { href: source.url.clone(), ..Default::default() }],
+        entries,
+        ..Default::default()
+    };
+    feed.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Article;
+    use serde_json::json;
+
+    fn stored(fields: Value, first_seen: &str, last_seen: &str) -> StoredArticle {
+        StoredArticle {
+            id: 1,
+            source: "news".to_string(),
+            first_seen: first_seen.to_string(),
+            last_seen: last_seen.to_string(),
+            hash: "abc".to_string(),
+            article: Article { fields: fields.as_object().unwrap().clone(), ..Article::default() },
+        }
+    }
+
+    fn source() -> Source {
+        toml::from_str("name = \"news\"\nurl = \"https://example.com/\"\nitem = \"li\"").unwrap()
+    }
+
+    #[test]
+    fn entries_are_dated_from_the_fields_then_the_store() {
+        let source = source();
+        let dated = stored(json!({ "title": "A", "published_at": "2024-03-01" }), "2024-03-05T10:00:00Z", "2024-03-06T10:00:00Z");
+        assert_eq!(entry(&source, &dated).date.to_rfc3339(), "2024-03-01T00:00:00+00:00");
+
+        let undated = stored(json!({ "title": "B" }), "2024-03-05T10:00:00Z", "2024-03-06T10:00:00Z");
+        assert_eq!(entry(&source, &undated).date.to_rfc3339(), "2024-03-05T10:00:00+00:00");
+
+        let unparsable = stored(json!({ "title": "C" }), "", "2024-03-06T10:00:00Z");
+        assert_eq!(entry(&source, &unparsable).date.to_rfc3339(), "2024-03-06T10:00:00+00:00");
+    }
+
+    #[test]
+    fn entries_without_any_date_are_not_dated_now() {
+        let articles = [stored(json!({ "title": "D", "link": "https://example.com/d" }), "", "")];
+        assert!(rss(&source(), &articles).contains("<pubDate>Thu, 1 Jan 1970 00:00:00 +0000</pubDate>"));
+    }
+}
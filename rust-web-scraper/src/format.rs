@@ -0,0 +1,126 @@
+use std::collections::BTreeSet;
+
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::models::StoredArticle;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Ndjson,
+    Rss,
+    Atom,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "ndjson" | "jsonl" => Some(Format::Ndjson),
+            "rss" => Some(Format::Rss),
+            "atom" => Some(Format::Atom),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+            Format::Rss => "rss",
+            Format::Atom => "atom",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Rss => "application/rss+xml; charset=utf-8",
+            Format::Atom => "application/atom+xml; charset=utf-8",
+        }
+    }
+
+    fn media_type(self) -> &'static str {
+        self.content_type().split(';').next().unwrap()
+    }
+}
+
+// Picks one of `offered` (the first is the default) from a ?format= parameter,
+// or else the Accept header. Only an unknown ?format= is an error; an Accept
+// header that matches nothing gets the default.
+pub fn negotiate(req: &HttpRequest, offered: &[Format]) -> Result<Format, Error> {
+    let param = url::form_urlencoded::parse(req.query_string().as_bytes()).find(|(k, _)| k == "format").map(|(_, v)| v.into_owned());
+    if let Some(name) = param {
+        let names: Vec<&str> = offered.iter().map(|f| f.name()).collect();
+        return Format::from_name(&name)
+            .filter(|f| offered.contains(f))
+            .ok_or_else(|| Error::BadRequest(format!("format must be one of {}", names.join(", "))));
+    }
+
+    let accept = req.headers().get("accept").and_then(|v| v.to_str().ok()).unwrap_or("");
+    let mut ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let media = parts.next().unwrap_or("");
+            let q = parts.find_map(|p| p.strip_prefix("q=")).and_then(|q| q.parse().ok()).unwrap_or(1.0);
+            (media, q)
+        })
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    for (media, _) in ranges {
+        if media == "*/*" {
+            break;
+        }
+        if let Some(f) = offered.iter().find(|f| f.media_type() == media) {
+            return Ok(*f);
+        }
+    }
+    Ok(offered[0])
+}
+
+// `json` as is, or the articles alone as CSV or one JSON object per line.
+pub fn respond(format: Format, json: Value, articles: &[&StoredArticle]) -> Result<HttpResponse, Error> {
+    let body = match format {
+        Format::Csv => csv(articles),
+        Format::Ndjson => ndjson(articles),
+        _ => return Ok(HttpResponse::Ok().json(json)),
+    };
+    let body = body.map_err(|e| Error::Internal(format!("writing {}: {}", format.name(), e)))?;
+    Ok(HttpResponse::Ok().content_type(format.content_type()).body(body))
+}
+
+fn ndjson(articles: &[&StoredArticle]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut out = String::new();
+    for article in articles {
+        out.push_str(&serde_json::to_string(article)?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+// One column per field any article has, in name order. Nested values are
+// written as JSON.
+fn csv(articles: &[&StoredArticle]) -> Result<String, Box<dyn std::error::Error>> {
+    let rows: Vec<Value> = articles.iter().map(serde_json::to_value).collect::<Result<_, _>>()?;
+    let columns: BTreeSet<&String> = rows.iter().filter_map(|r| r.as_object()).flat_map(|r| r.keys()).collect();
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&columns)?;
+    for row in &rows {
+        writer.write_record(columns.iter().map(|c| match row.get(c.as_str()) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s.clone(),
+            Some(other) => other.to_string(),
+        }))?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, timeout};
+use url::Url;
+
+use crate::error::Error;
+
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub user_agent: String,
+    pub headers: BTreeMap<String, String>,
+    pub connect_timeout_ms: u64,
+    // How long to wait for the response headers, and then for each chunk of
+    // the body.
+    pub read_timeout_ms: u64,
+    // Requests in flight at once, across all hosts.
+    pub max_concurrency: usize,
+    // Token bucket per host: requests per second, and how many may go out
+    // back to back after a quiet spell.
+    pub rate_per_host: f64,
+    pub burst: u32,
+    pub retries: u32,
+    pub backoff_ms: u64,
+    // The longest wait between retries, Retry-After included.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            user_agent: USER_AGENT.to_string(),
+            headers: BTreeMap::new(),
+            connect_timeout_ms: 10_000,
+            read_timeout_ms: 30_000,
+            max_concurrency: 8,
+            rate_per_host: 2.0,
+            burst: 4,
+            retries: 3,
+            backoff_ms: 500,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+pub struct Fetched {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+// The one HTTP client everything fetches through, so limits hold across
+// concurrent API requests and crawls.
+pub struct Http {
+    client: reqwest::Client,
+    config: HttpConfig,
+    slots: Semaphore,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Http {
+    pub fn new(config: &HttpConfig) -> Result<Http, Box<dyn std::error::Error>> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            headers.insert(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+        }
+        let client = reqwest::Client::builder()
+            .user_agent(config.user_agent.as_str())
+            .default_headers(headers)
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .build()?;
+        Ok(Http {
+            client,
+            config: config.clone(),
+            slots: Semaphore::new(config.max_concurrency.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.config.user_agent
+    }
+
+    // Waits until the host's bucket has a token to spend.
+    async fn throttle(&self, url: &Url) {
+        if self.config.rate_per_host <= 0.0 {
+            return;
+        }
+        let host = url.host_str().unwrap_or("").to_string();
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().unwrap();
+                let burst = self.config.burst.max(1) as f64;
+                let bucket = buckets.entry(host.clone()).or_insert(Bucket { tokens: burst, refilled: Instant::now() });
+                let now = Instant::now();
+                bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * self.config.rate_per_host).min(burst);
+                bucket.refilled = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.config.rate_per_host)
+            };
+            sleep(wait).await;
+        }
+    }
+
+    // Exponential backoff with jitter, unless the server said how long to
+    // wait. Either way it is capped at max_backoff_ms, as a Retry-After of
+    // hours would otherwise stall the whole scrape.
+    fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(d) = retry_after {
+            return d.min(Duration::from_millis(self.config.max_backoff_ms));
+        }
+        let base = self.config.backoff_ms.saturating_mul(1 << attempt.min(16)).min(self.config.max_backoff_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(base / 2..=base))
+    }
+
+    // Errors carry whether they are worth retrying, and how long the server
+    // asked us to wait.
+    async fn attempt(&self, url: &Url, headers: &HeaderMap) -> Result<Fetched, (Error, bool, Option<Duration>)> {
+        let read = Duration::from_millis(self.config.read_timeout_ms);
+        let mut resp = match timeout(read, self.client.get(url.clone()).headers(headers.clone()).send()).await {
+            Ok(Ok(resp)) => resp,
+            Ok(Err(e)) => {
+                let retry = e.is_timeout() || e.is_connect();
+                return Err((Error::from_reqwest(url.as_str(), e), retry, None));
+            }
+            Err(_) => return Err((Error::Timeout(format!("{}: timed out waiting for a response", url)), true, None)),
+        };
+        let status = resp.status();
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            let error = Error::Upstream { url: url.to_string(), status: status.as_u16() };
+            return Err((error, true, retry_after(resp.headers())));
+        }
+
+        let mut body = Vec::new();
+        loop {
+            match timeout(read, resp.chunk()).await {
+                Ok(Ok(Some(chunk))) => body.extend_from_slice(&chunk),
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => return Err((Error::from_reqwest(url.as_str(), e), true, None)),
+                Err(_) => return Err((Error::Timeout(format!("{}: timed out reading the response", url)), true, None)),
+            }
+        }
+        Ok(Fetched { status, headers: resp.headers().clone(), body: String::from_utf8_lossy(&body).into_owned() })
+    }
+
+    pub async fn get(&self, url: &Url) -> Result<Fetched, Error> {
+        self.request(url, &HeaderMap::new()).await
+    }
+
+    // Retries 429s, 5xx responses, timeouts and failed connections. Any
+    // other status, 304 included, is handed back as is.
+    pub async fn request(&self, url: &Url, headers: &HeaderMap) -> Result<Fetched, Error> {
+        let mut attempt = 0;
+        loop {
+            self.throttle(url).await;
+            let permit = self.slots.acquire().await.map_err(|e| Error::Internal(e.to_string()))?;
+            let result = self.attempt(url, headers).await;
+            drop(permit);
+
+            match result {
+                Ok(response) => return Ok(response),
+                Err((e, true, wait)) if attempt < self.config.retries => {
+                    let wait = self.backoff(attempt, wait);
+                    eprintln!("{}; retrying in {:?}", e, wait);
+                    sleep(wait).await;
+                    attempt += 1;
+                }
+                Err((e, _, _)) => return Err(e),
+            }
+        }
+    }
+}
+
+// Retry-After is either a number of seconds or an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // A server on a free local port that answers the nth request with
+    // `responses[n]` (status line and headers), repeating the last one, and
+    // hands back when each request came in.
+    fn server(responses: &[&str]) -> (Url, mpsc::Receiver<Instant>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/page", listener.local_addr().unwrap())).unwrap();
+        let responses: Vec<String> = responses.iter().map(|r| r.to_string()).collect();
+        let (sender, received) = mpsc::channel();
+        thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let _ = sender.send(Instant::now());
+                let response = &responses[n.min(responses.len() - 1)];
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok", response).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn http(config: HttpConfig) -> Http {
+        Http::new(&HttpConfig { rate_per_host: 0.0, backoff_ms: 1, ..config }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (url, received) = server(&["503 Service Unavailable", "500 Internal Server Error", "200 OK"]);
+        let fetched = http(HttpConfig::default()).get(&url).await.unwrap();
+        assert_eq!(fetched.status, StatusCode::OK);
+        assert_eq!(fetched.body, "ok");
+        assert_eq!(received.try_iter().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_run_out() {
+        let (url, received) = server(&["502 Bad Gateway"]);
+        let result = http(HttpConfig { retries: 2, ..HttpConfig::default() }).get(&url).await;
+        assert!(matches!(result, Err(Error::Upstream { status: 502, .. })));
+        assert_eq!(received.try_iter().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, received) = server(&["404 Not Found", "200 OK"]);
+        let fetched = http(HttpConfig::default()).get(&url).await.unwrap();
+        assert_eq!(fetched.status, StatusCode::NOT_FOUND);
+        assert_eq!(received.try_iter().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_after_is_waited_out() {
+        let (url, received) = server(&["429 Too Many Requests\r\nRetry-After: 1", "200 OK"]);
+        http(HttpConfig { max_backoff_ms: 5_000, ..HttpConfig::default() }).get(&url).await.unwrap();
+        let times: Vec<Instant> = received.try_iter().collect();
+        assert_eq!(times.len(), 2);
+        assert!(times[1] - times[0] >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn retry_after_is_capped_at_max_backoff() {
+        let (url, received) = server(&["503 Service Unavailable\r\nRetry-After: 3600", "200 OK"]);
+        let started = Instant::now();
+        http(HttpConfig { max_backoff_ms: 200, ..HttpConfig::default() }).get(&url).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let times: Vec<Instant> = received.try_iter().collect();
+        assert!(times[1] - times[0] >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn retry_after_takes_seconds_or_a_date() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        let date = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&date).unwrap());
+        let wait = retry_after(&headers).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+
+        // A date in the past means no wait at all rather than a negative one.
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let http = Http::new(&HttpConfig { backoff_ms: 100, max_backoff_ms: 1_000, ..HttpConfig::default() }).unwrap();
+        for (attempt, base) in [(0, 100), (1, 200), (2, 400), (3, 800), (4, 1_000), (20, 1_000)] {
+            let wait = http.backoff(attempt, None);
+            assert!(wait >= Duration::from_millis(base / 2) && wait <= Duration::from_millis(base), "attempt {}: {:?}", attempt, wait);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_burst_wait_for_tokens() {
+        let (url, received) = server(&["200 OK"]);
+        let http = Http::new(&HttpConfig { rate_per_host: 10.0, burst: 2, ..HttpConfig::default() }).unwrap();
+        for _ in 0..4 {
+            http.get(&url).await.unwrap();
+        }
+        let times: Vec<Instant> = received.try_iter().collect();
+        assert_eq!(times.len(), 4);
+        // The burst goes out at once, then one request every 100ms.
+        assert!(times[1] - times[0] < Duration::from_millis(50));
+        assert!(times[3] - times[1] >= Duration::from_millis(190));
+    }
+}
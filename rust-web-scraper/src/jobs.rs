@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::cache::{Cache, Tally};
+use crate::config::Source;
+use crate::error::Error;
+use crate::http::Http;
+use crate::notify::Notifier;
+use crate::scraper;
+use crate::search::Search;
+use crate::store::{self, Store};
+
+const DEFAULT_EVERY: Duration = Duration::from_secs(60 * 60);
+
+pub enum Schedule {
+    Every(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    // A source runs on its cron expression, or every so often (hourly if it
+    // says neither).
+    pub fn parse(source: &Source) -> Result<Schedule, Error> {
+        match (&source.every, &source.cron) {
+            (Some(_), Some(_)) => Err(Error::Parse("every and cron can't both be set".to_string())),
+            (Some(every), None) => parse_period(every).map(Schedule::Every),
+            (None, Some(expr)) => cron::Schedule::from_str(expr)
+                .map(|s| Schedule::Cron(Box::new(s)))
+                .map_err(|e| Error::Parse(format!("invalid cron expression {:?}: {}", expr, e))),
+            (None, None) => Ok(Schedule::Every(DEFAULT_EVERY)),
+        }
+    }
+
+    fn until_next(&self) -> Duration {
+        match self {
+            Schedule::Every(period) => *period,
+            Schedule::Cron(schedule) => schedule
+                .upcoming(Utc)
+                .next()
+                .and_then(|at| (at - Utc::now()).to_std().ok())
+                .unwrap_or(DEFAULT_EVERY),
+        }
+    }
+}
+
+// "30s", "15m", "6h" or "1d".
+fn parse_period(s: &str) -> Result<Duration, Error> {
+    let invalid = || Error::Parse(format!("invalid period {:?}, expected e.g. 30s, 15m, 6h or 1d", s));
+    let s = s.trim();
+    let unit = s.chars().last().ok_or_else(invalid)?;
+    let n: u64 = s[..s.len() - unit.len_utf8()].parse().map_err(|_| invalid())?;
+    let secs = match unit {
+        's' => n,
+        'm' => n * 60,
+        'h' => n * 60 * 60,
+        'd' => n * 60 * 60 * 24,
+        _ => return Err(invalid()),
+    };
+    if secs == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct JobStatus {
+    pub running: bool,
+    pub last_run: Option<String>,
+    pub duration_ms: Option<u64>,
+    pub items: Option<usize>,
+    // Articles not seen before, and ones whose fields differ from last time.
+    pub new: Option<usize>,
+    pub changed: Option<usize>,
+    pub ok: Option<bool>,
+    pub last_error: Option<String>,
+    pub last_error_code: Option<&'static str>,
+    pub last_error_at: Option<String>,
+    pub next_run: Option<String>,
+}
+
+// What each source's job last did, for /jobs.
+#[derive(Default)]
+pub struct Jobs {
+    status: Mutex<BTreeMap<String, JobStatus>>,
+}
+
+impl Jobs {
+    pub fn status(&self) -> BTreeMap<String, JobStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn update(&self, source: &str, f: impl FnOnce(&mut JobStatus)) {
+        f(self.status.lock().unwrap().entry(source.to_string()).or_default());
+    }
+}
+
+// Starts one task per source. Each runs straight away, so there is something
+// to serve, and then on its schedule.
+pub fn start(sources: &[Source], http: web::Data<Http>, cache: web::Data<Cache>, store: web::Data<Store>, search: web::Data<Search>, notifier: web::Data<Notifier>, jobs: web::Data<Jobs>) {
+    for source in sources {
+        // Checked when the config was loaded.
+        let schedule = Schedule::parse(source).unwrap();
+        jobs.update(&source.name, |_| ());
+        let (source, http, cache, store, search, notifier, jobs) =
+            (source.clone(), http.clone(), cache.clone(), store.clone(), search.clone(), notifier.clone(), jobs.clone());
+        actix_web::rt::spawn(async move {
+            loop {
+                run(&source, &http, &cache, &store, &search, &notifier, &jobs).await;
+                let wait = schedule.until_next();
+                jobs.update(&source.name, |s| s.next_run = chrono::Duration::from_std(wait).ok().map(|d| store::timestamp(Utc::now() + d)));
+                actix_web::rt::time::sleep(wait).await;
+            }
+        });
+    }
+}
+
+async fn run(source: &Source, http: &Http, cache: &Cache, store: &web::Data<Store>, search: &web::Data<Search>, notifier: &web::Data<Notifier>, jobs: &Jobs) {
+    jobs.update(&source.name, |s| s.running = true);
+    let started = Instant::now();
+    let last_run = store::timestamp(Utc::now());
+
+    let result = match scraper::scrape_articles(http, cache, source, &mut Tally::default()).await {
+        Ok(articles) => {
+            let (store, name, key) = (store.clone(), source.name.clone(), source.key.clone());
+            store::blocking(move || store.save(&name, &key, &articles).map(|changes| (articles, changes))).await
+        }
+        Err(e) => Err(e),
+    };
+
+    if let Ok((_, changes)) = &result {
+        let ids: Vec<i64> = changes.new.iter().chain(&changes.changed).map(|&i| changes.ids[i]).collect();
+        // The store has them either way; the index catches up on the next
+        // update or when the server restarts.
+        let (store, search) = (store.clone(), search.clone());
+        if let Err(e) = store::blocking(move || search.update(&store, &ids)).await {
+            eprintln!("job {}: {}", source.name, e);
+        }
+    }
+
+    // The first run only sets the baseline to compare against.
+    if let Ok((articles, changes)) = &result {
+        let quiet = changes.new.is_empty() && changes.changed.is_empty();
+        if !changes.first && !quiet {
+            let payload = json!({
+                "source": source.name,
+                "detected_at": last_run,
+                "new": changes.new.iter().map(|&i| &articles[i]).collect::<Vec<_>>(),
+                "changed": changes.changed.iter().map(|&i| &articles[i]).collect::<Vec<_>>(),
+            });
+            Notifier::notify(notifier, &source.name, payload);
+        }
+    }
+
+    let duration_ms = started.elapsed().as_millis() as u64;
+    jobs.update(&source.name, |s| {
+        s.running = false;
+        s.last_run = Some(last_run);
+        s.duration_ms = Some(duration_ms);
+        s.ok = Some(result.is_ok());
+        s.items = result.as_ref().ok().map(|(articles, _)| articles.len());
+        s.new = result.as_ref().ok().map(|(_, changes)| changes.new.len());
+        s.changed = result.as_ref().ok().map(|(_, changes)| changes.changed.len());
+        if let Err(e) = result {
+            eprintln!("job {}: {}", source.name, e);
+            s.last_error = Some(e.to_string());
+            s.last_error_code = Some(e.code());
+            s.last_error_at = s.last_run.clone();
+        }
+    });
+}
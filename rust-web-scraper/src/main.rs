@@ -1,22 +1,337 @@
-use actix_web::{web, App, HttpServer, Responder, HttpResponse};
-use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+
+use actix_web::{web, App, HttpRequest, HttpServer, Responder, HttpResponse, HttpResponseBuilder};
+use serde::Deserialize;
+use ::scraper::Html;
+use serde_json::{json, Map};
+use url::Url;
+mod config;
 mod scraper;
 mod models;
- 
+mod schema;
+mod urls;
+mod robots;
+mod crawler;
+mod http;
+mod cache;
+mod store;
+mod jobs;
+mod format;
+mod feed;
+mod notify;
+mod error;
+mod metadata;
+mod readability;
+mod search;
+mod extractors;
+
+use config::{Config, Source};
+use cache::{Cache, Tally};
+use http::Http;
+use store::Store;
+use jobs::Jobs;
+use format::Format;
+use notify::Notifier;
+use error::{ApiError, Error};
+use search::Search;
+use schema::{Field, FieldSpec, FieldType};
+
+const DEFAULT_CONFIG: &str = "scraper.toml";
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
+    let path = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_CONFIG.to_string());
+    let config = config::load(&path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let bind = config.bind.clone();
+    let http = Http::new(&config.http)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let cache = web::Data::new(Cache::new(&config.cache));
+    let store = Store::open(&config.store)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let search = Search::open(&config.search)
+        .and_then(|search| search.sync(&store).map(|_| search))
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let store = web::Data::new(store);
+    let search = web::Data::new(search);
+    let config = web::Data::new(config);
+    let http = web::Data::new(http);
+    let notifier = Notifier::new(&config.notify)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let notifier = web::Data::new(notifier);
+    let jobs = web::Data::new(Jobs::default());
+    jobs::start(&config.sources, http.clone(), cache.clone(), store.clone(), search.clone(), notifier, jobs.clone());
+
+    HttpServer::new(move || {
         App::new()
+            .app_data(config.clone())
+            .app_data(http.clone())
+            .app_data(cache.clone())
+            .app_data(store.clone())
+            .app_data(search.clone())
+            .app_data(jobs.clone())
+            .app_data(web::JsonConfig::default().error_handler(|e, _| ApiError::from(Error::BadRequest(e.to_string())).into()))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| ApiError::from(Error::BadRequest(e.to_string())).into()))
             .route("/", web::get().to(get_articles))
+            .route("/sources/{name}", web::get().to(get_source))
+            .route("/sources/{name}/feed.xml", web::get().to(get_feed))
+            .route("/articles", web::get().to(list_articles))
+            .route("/search", web::get().to(search_articles))
+            .route("/jobs", web::get().to(get_jobs))
+            .route("/extract", web::get().to(extract))
+            .route("/scrape", web::get().to(scrape_query))
+            .route("/scrape", web::post().to(scrape_json))
     })
-    .bind("127.0.0.1:8080")?
+    .bind(bind)?
     .run()
     .await
 }
- 
-async fn get_articles() -> impl Responder {
-    match scraper::scrape_articles().await {
-        Ok(articles) => HttpResponse::Ok().json(json!({ "articles": articles })),
-        Err(_) => HttpResponse::InternalServerError().body("Failed to retrieve articles"),
+
+// A 200 whose headers say what the cache did for the pages behind it.
+fn ok_cached(tally: &Tally) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header(("X-Cache", tally.status()))
+        .insert_header(("X-Cache-Hits", (tally.hits + tally.revalidated).to_string()))
+        .insert_header(("X-Cache-Misses", tally.misses.to_string()));
+    builder
+}
+
+const TABULAR: [Format; 3] = [Format::Json, Format::Csv, Format::Ndjson];
+
+type Response = Result<HttpResponse, ApiError>;
+
+fn find<'a>(config: &'a Config, name: &str) -> Result<&'a Source, ApiError> {
+    config.source(name).ok_or_else(|| ApiError::for_source(name)(Error::UnknownSource(name.to_string())))
+}
+
+// Sources are scraped by their jobs; these read what the last run stored.
+async fn get_articles(req: HttpRequest, config: web::Data<Config>, store: web::Data<Store>) -> Response {
+    let format = format::negotiate(&req, &TABULAR)?;
+    let mut current = Vec::new();
+    for source in &config.sources {
+        let (store, name) = (store.clone(), source.name.clone());
+        let articles = store::blocking(move || store.current(&name)).await.map_err(ApiError::for_source(&source.name))?;
+        current.push((source, articles));
+    }
+    let mut sources = Map::new();
+    for (source, articles) in &current {
+        sources.insert(source.name.clone(), json!({ "articles": articles }));
+    }
+    let rows: Vec<_> = current.iter().flat_map(|(_, articles)| articles).collect();
+    Ok(format::respond(format, json!({ "sources": sources }), &rows)?)
+}
+
+async fn get_source(req: HttpRequest, config: web::Data<Config>, store: web::Data<Store>, name: web::Path<String>) -> Response {
+    let source = find(&config, &name)?;
+    let format = format::negotiate(&req, &TABULAR)?;
+    let name = source.name.clone();
+    let articles = store::blocking(move || store.current(&name)).await.map_err(ApiError::for_source(&source.name))?;
+    let rows: Vec<_> = articles.iter().collect();
+    Ok(format::respond(format, json!({ "source": source.name, "articles": articles }), &rows)?)
+}
+
+const FEED_LENGTH: usize = 50;
+
+// RSS unless Atom is asked for, with the latest articles ever seen rather
+// than only the ones on the page now, so readers don't miss any.
+async fn get_feed(req: HttpRequest, config: web::Data<Config>, store: web::Data<Store>, name: web::Path<String>) -> Response {
+    let source = find(&config, &name)?;
+    let format = format::negotiate(&req, &[Format::Rss, Format::Atom])?;
+    let name = source.name.clone();
+    let (articles, _) = store::blocking(move || store.articles(&store::Query { source: Some(&name), since: None, limit: FEED_LENGTH, offset: 0 }))
+        .await
+        .map_err(ApiError::for_source(&source.name))?;
+    let body = match format {
+        Format::Atom => feed::atom(source, &articles),
+        _ => feed::rss(source, &articles),
+    };
+    Ok(HttpResponse::Ok().content_type(format.content_type()).body(body))
+}
+
+async fn get_jobs(jobs: web::Data<Jobs>) -> impl Responder {
+    HttpResponse::Ok().json(json!({ "jobs": jobs.status() }))
+}
+
+#[derive(Deserialize)]
+struct ArticlesQuery {
+    source: Option<String>,
+    since: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+// Everything the store has seen, newest first.
+async fn list_articles(req: HttpRequest, store: web::Data<Store>, query: web::Query<ArticlesQuery>) -> Response {
+    let format = format::negotiate(&req, &TABULAR)?;
+    let since = match query.since.as_deref().map(store::parse_since) {
+        Some(None) => return Err(Error::Parse("since must be a date (YYYY-MM-DD) or an RFC 3339 time".to_string()).into()),
+        Some(since) => since,
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+    let source = query.source.clone();
+    let (articles, total) = store::blocking(move || store.articles(&store::Query { source: source.as_deref(), since, limit, offset })).await?;
+    let rows: Vec<_> = articles.iter().collect();
+    Ok(format::respond(format, json!({ "articles": articles, "total": total, "limit": limit, "offset": offset }), &rows)?)
+}
+
+#[derive(Deserialize)]
+struct ExtractQuery {
+    url: String,
+}
+
+// The readable text of any one page.
+async fn extract(http: web::Data<Http>, cache: web::Data<Cache>, query: web::Query<ExtractQuery>) -> Response {
+    let url = Url::parse(&query.url).map_err(Error::from)?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(Error::BadRequest("url must be http or https".to_string()).into());
     }
-}
\ No newline at end of file
+    let mut tally = Tally::default();
+    let body = cache.fetch(&http, "extract", &url, &mut tally).await?;
+    let content = readability::extract(&Html::parse_document(&body), &url).ok_or_else(|| Error::NoContent(url.to_string()))?;
+    Ok(ok_cached(&tally).json(json!({ "url": url.as_str(), "content": content })))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    source: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+// Everything the store has seen, best match first.
+async fn search_articles(req: HttpRequest, store: web::Data<Store>, search: web::Data<Search>, query: web::Query<SearchQuery>) -> Response {
+    let format = format::negotiate(&req, &TABULAR)?;
+    let time = |name: &str, value: &Option<String>| match value.as_deref().map(store::parse_since) {
+        Some(None) => Err(Error::Parse(format!("{} must be a date (YYYY-MM-DD) or an RFC 3339 time", name))),
+        Some(time) => Ok(time),
+        None => Ok(None),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+    let (since, until) = (time("since", &query.since)?, time("until", &query.until)?);
+    let (q, source) = (query.q.clone(), query.source.clone());
+    let (hits, total, articles) = store::blocking(move || {
+        let filter = search::Query { q: &q, source: source.as_deref(), since, until, limit, offset };
+        let (hits, total) = search.search(&filter)?;
+        let articles = store.by_id(&hits.iter().map(|h| h.id).collect::<Vec<_>>())?;
+        Ok((hits, total, articles))
+    })
+    .await?;
+    let results: Vec<_> = hits
+        .iter()
+        .filter_map(|hit| {
+            let article = articles.iter().find(|a| a.id == hit.id)?;
+            Some(json!({ "score": hit.score, "highlights": { "title": hit.title, "body": hit.snippet }, "article": article }))
+        })
+        .collect();
+    let rows: Vec<_> = hits.iter().filter_map(|hit| articles.iter().find(|a| a.id == hit.id)).collect();
+    Ok(format::respond(format, json!({ "query": query.q, "results": results, "total": total, "limit": limit, "offset": offset }), &rows)?)
+}
+
+// A one-off scrape, for trying out selectors before adding a source.
+#[derive(Deserialize)]
+struct AdHoc {
+    url: String,
+    item: Option<String>,
+    #[serde(default)]
+    fields: BTreeMap<String, FieldSpec>,
+    #[serde(default)]
+    metadata: Option<metadata::Mode>,
+    #[serde(default)]
+    content: bool,
+}
+
+async fn scrape_adhoc(http: &Http, cache: &Cache, adhoc: AdHoc) -> Response {
+    let source = Source {
+        name: "adhoc".to_string(),
+        url: adhoc.url,
+        item: adhoc.item,
+        fields: adhoc.fields,
+        key: config::default_key(),
+        crawl: None,
+        every: None,
+        cron: None,
+        metadata: adhoc.metadata,
+        content: adhoc.content,
+    };
+    source.check()?;
+    let mut tally = Tally::default();
+    let articles = scraper::scrape_articles(http, cache, &source, &mut tally).await?;
+    Ok(ok_cached(&tally).json(json!({ "articles": articles })))
+}
+
+// Every parameter besides url and item is a field. A selector ending in
+// @name takes that attribute, e.g. link=a@href, and links are resolved. A
+// link without one takes the href, as it would be no use as text.
+fn adhoc_query(mut params: HashMap<String, String>) -> Result<AdHoc, Error> {
+    let (url, item) = match params.remove("url") {
+        Some(url) => (url, params.remove("item")),
+        None => return Err(Error::BadRequest("url is required".to_string())),
+    };
+    let fields = params.into_iter().map(|(name, selector)| {
+        let attr = |s: &str, attr: &str| {
+            let kind = if attr == "href" || attr == "src" { FieldType::Url } else { FieldType::String };
+            FieldSpec::Field(Field {
+                selector: Some(s.to_string()),
+                attr: Some(attr.to_string()),
+                kind,
+                ..Field::default()
+            })
+        };
+        let spec = match selector.rsplit_once('@') {
+            Some((s, a)) if !a.is_empty() && a.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => attr(s, a),
+            _ if name == "link" => attr(&selector, "href"),
+            _ => FieldSpec::Selector(selector),
+        };
+        (name, spec)
+    }).collect();
+    Ok(AdHoc { url, item, fields, metadata: None, content: false })
+}
+
+async fn scrape_query(http: web::Data<Http>, cache: web::Data<Cache>, query: web::Query<HashMap<String, String>>) -> Response {
+    scrape_adhoc(&http, &cache, adhoc_query(query.into_inner())?).await
+}
+
+async fn scrape_json(http: web::Data<Http>, cache: web::Data<Cache>, adhoc: web::Json<AdHoc>) -> Response {
+    scrape_adhoc(&http, &cache, adhoc.into_inner()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::scraper::Selector;
+    use url::Url;
+
+    use crate::schema::Schema;
+
+    #[test]
+    fn query_string_fields() {
+        let query = web::Query::<HashMap<String, String>>::from_query("url=https://example.com/news&item=article&title=h2&link=a&image=img@src").unwrap();
+        let adhoc = adhoc_query(query.into_inner()).unwrap();
+        assert_eq!(adhoc.url, "https://example.com/news");
+        assert_eq!(adhoc.item.as_deref(), Some("article"));
+
+        let html = Html::parse_document(r#"<article><h2>First post</h2><a href="/posts/1">Read more</a><img src="1.png"></article>"#);
+        let item = html.select(&Selector::parse("article").unwrap()).next().unwrap();
+        let schema = Schema::compile(&adhoc.fields).unwrap();
+        let fields = schema.extract(item, &Url::parse(&adhoc.url).unwrap()).unwrap();
+        assert_eq!(fields["title"], "First post");
+        assert_eq!(fields["link"], "https://example.com/posts/1");
+        assert_eq!(fields["image"], "https://example.com/1.png");
+    }
+
+    #[test]
+    fn query_string_without_url() {
+        let query = web::Query::<HashMap<String, String>>::from_query("item=article&title=h2").unwrap();
+        assert!(matches!(adhoc_query(query.into_inner()), Err(Error::BadRequest(_))));
+    }
+}
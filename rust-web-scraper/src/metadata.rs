@@ -0,0 +1,202 @@
+use scraper::Html;
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+use crate::models::Article;
+use crate::schema::{parse_date, parse_selector};
+use crate::urls;
+
+// Where to look for an article's description, image and so on.
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    // The listing page itself.
+    Page,
+    // Each article's own page, fetched through its link.
+    Article,
+}
+
+const ARTICLE_TYPES: [&str; 5] = ["Article", "NewsArticle", "BlogPosting", "ReportageNewsArticle", "TechArticle"];
+
+#[derive(Default, Clone)]
+pub struct Metadata {
+    pub url: Option<Url>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub author: Option<String>,
+    pub published_at: Option<String>,
+    pub site_name: Option<String>,
+}
+
+impl Metadata {
+    // Fills what `self` lacks from `other`.
+    fn or(mut self, other: Metadata) -> Metadata {
+        self.url = self.url.or(other.url);
+        self.description = self.description.or(other.description);
+        self.image = self.image.or(other.image);
+        self.author = self.author.or(other.author);
+        self.published_at = self.published_at.or(other.published_at);
+        self.site_name = self.site_name.or(other.site_name);
+        self
+    }
+
+    // What the article already has is left alone.
+    pub fn apply(self, article: &mut Article) {
+        let m = &mut article.metadata;
+        fill(&mut m.description, self.description);
+        fill(&mut m.image, self.image);
+        fill(&mut m.author, self.author);
+        fill(&mut m.published_at, self.published_at);
+        fill(&mut m.site_name, self.site_name);
+    }
+}
+
+fn fill(slot: &mut Option<String>, value: Option<String>) {
+    if slot.is_none() {
+        *slot = value;
+    }
+}
+
+fn clean(s: &str) -> Option<String> {
+    let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+// The content of the first <meta> with one of these property or name values.
+pub fn meta(document: &Html, names: &[&str]) -> Option<String> {
+    let selector = parse_selector("meta[content]").unwrap();
+    names.iter().find_map(|name| {
+        document
+            .select(&selector)
+            .find(|m| m.value().attr("property").or_else(|| m.value().attr("name")).map(|p| p.eq_ignore_ascii_case(name)).unwrap_or(false))
+            .and_then(|m| m.value().attr("content"))
+            .and_then(clean)
+    })
+}
+
+// OpenGraph first, then Twitter cards, then plain <meta> tags.
+fn tags(document: &Html, base: &Url) -> Metadata {
+    Metadata {
+        url: meta(document, &["og:url"]).and_then(|u| urls::resolve(base, &u)),
+        description: meta(document, &["og:description", "twitter:description", "description"]),
+        image: meta(document, &["og:image", "og:image:url", "twitter:image", "twitter:image:src"])
+            .and_then(|u| urls::resolve(base, &u))
+            .map(String::from),
+        author: meta(document, &["article:author", "twitter:creator", "author"]),
+        published_at: meta(document, &["article:published_time", "og:published_time", "date"]).and_then(|d| parse_date(&d)),
+        site_name: meta(document, &["og:site_name", "application-name"]),
+    }
+}
+
+// A name out of a string, an object with a name, or a list of either.
+fn name(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => clean(s),
+        Value::Object(o) => o.get("name").and_then(name),
+        Value::Array(a) => {
+            let names: Vec<String> = a.iter().filter_map(name).collect();
+            if names.is_empty() {
+                None
+            } else {
+                Some(names.join(", "))
+            }
+        }
+        _ => None,
+    }
+}
+
+// A URL out of a string, an object with a url or @id, or the first of a list.
+fn link(value: &Value, base: &Url) -> Option<Url> {
+    match value {
+        Value::String(s) => urls::resolve(base, s),
+        Value::Object(o) => o.get("url").or_else(|| o.get("@id")).and_then(|v| link(v, base)),
+        Value::Array(a) => a.iter().find_map(|v| link(v, base)),
+        _ => None,
+    }
+}
+
+fn is_article(object: &serde_json::Map<String, Value>) -> bool {
+    match object.get("@type") {
+        Some(Value::String(t)) => ARTICLE_TYPES.contains(&t.as_str()),
+        Some(Value::Array(types)) => types.iter().any(|t| t.as_str().map(|t| ARTICLE_TYPES.contains(&t)).unwrap_or(false)),
+        _ => false,
+    }
+}
+
+// Article objects anywhere in the value: at the top, in a list or an
+// @graph, or nested as the items of an ItemList.
+fn articles<'a>(value: &'a Value, found: &mut Vec<&'a serde_json::Map<String, Value>>) {
+    match value {
+        Value::Array(a) => a.iter().for_each(|v| articles(v, found)),
+        Value::Object(o) => {
+            if is_article(o) {
+                found.push(o);
+            }
+            for key in ["@graph", "itemListElement", "item", "mainEntity"] {
+                if let Some(v) = o.get(key) {
+                    articles(v, found);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+// Every Article-like block in the page's JSON-LD.
+fn json_ld(document: &Html, base: &Url) -> Vec<Metadata> {
+    let selector = parse_selector("script[type=\"application/ld+json\"]").unwrap();
+    let mut found = Vec::new();
+    for script in document.select(&selector) {
+        // Sites ship broken JSON-LD often enough that it's just skipped.
+        let value: Value = match serde_json::from_str(&script.text().collect::<String>()) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let mut objects = Vec::new();
+        articles(&value, &mut objects);
+        found.extend(objects.into_iter().map(|o| Metadata {
+            url: o.get("url").or_else(|| o.get("mainEntityOfPage")).and_then(|v| link(v, base)),
+            description: o.get("description").and_then(|v| v.as_str()).and_then(clean),
+            image: o.get("image").and_then(|v| link(v, base)).map(String::from),
+            author: o.get("author").and_then(name),
+            published_at: o.get("datePublished").and_then(|v| v.as_str()).and_then(parse_date),
+            site_name: o.get("publisher").and_then(name),
+        }));
+    }
+    found
+}
+
+// What the page says about itself, JSON-LD first.
+pub fn document(document: &Html, base: &Url) -> Metadata {
+    let tags = tags(document, base);
+    match json_ld(document, base).into_iter().next() {
+        Some(ld) => ld.or(tags),
+        None => tags,
+    }
+}
+
+pub fn article_url(article: &Article, key: &str) -> Option<String> {
+    ["link", "url", key].iter().find_map(|name| article.fields.get(*name).and_then(|v| v.as_str()).map(String::from))
+}
+
+// On a listing page, JSON-LD blocks are matched to items by URL and the site
+// name goes on all of them. A page with a single item is taken to be that
+// item, so it gets everything.
+pub fn from_listing(doc: &Html, base: &Url, key: &str, articles: &mut [Article]) {
+    if let [article] = articles {
+        document(doc, base).apply(article);
+        return;
+    }
+    let blocks = json_ld(doc, base);
+    let site_name = tags(doc, base).site_name;
+    for article in articles.iter_mut() {
+        let url = article_url(article, key).and_then(|u| Url::parse(&u).ok()).map(urls::normalize);
+        let block = blocks.iter().find(|b| b.url.is_some() && b.url == url).cloned().unwrap_or_default();
+        block.or(Metadata { site_name: site_name.clone(), ..Metadata::default() }).apply(article);
+    }
+}
@@ -1,7 +1,77 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
-#[derive(Serialize)]
+use crate::readability::Content;
+
+// Names an article uses for itself, next to its fields, so no schema field
+// can have them.
+pub const RESERVED: [&str; 2] = ["metadata", "content"];
+
+// One scraped item, holding whatever fields its source's schema defines, what
+// the page's metadata says about it, and maybe its full text.
+#[derive(Serialize, Deserialize, Default)]
 pub struct Article {
-    pub title: String,
-    pub link: String,
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+    // Kept under its own key, as schemas often have fields of the same names.
+    #[serde(default, skip_serializing_if = "PageMetadata::is_empty")]
+    pub metadata: PageMetadata,
+    // The article's own text, for sources that fetch it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Content>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct PageMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+}
+
+impl PageMetadata {
+    pub fn is_empty(&self) -> bool {
+        *self == PageMetadata::default()
+    }
+}
+
+// An article as kept in the store, with when it was first and last scraped
+// and a hash of its fields.
+#[derive(Serialize)]
+pub struct StoredArticle {
+    #[serde(skip)]
+    pub id: i64,
+    pub source: String,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub hash: String,
+    #[serde(flatten)]
+    pub article: Article,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fields_and_metadata_of_the_same_name_survive_a_round_trip() {
+        let mut article = Article::default();
+        article.fields.insert("author".to_string(), json!("pg"));
+        article.fields.insert("published_at".to_string(), json!("2024-01-02T03:04:05Z"));
+        article.metadata.author = Some("Paul Graham".to_string());
+        article.metadata.site_name = Some("Hacker News".to_string());
+
+        let json = serde_json::to_string(&article).unwrap();
+        let read: Article = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.fields, article.fields);
+        assert_eq!(read.metadata, article.metadata);
+        assert_eq!(serde_json::to_value(&read).unwrap(), serde_json::to_value(&article).unwrap());
+    }
 }
@@ -0,0 +1,220 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Duration;
+
+use actix_web::web;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::store;
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    #[serde(rename = "webhook")]
+    pub webhooks: Vec<Webhook>,
+    pub retries: u32,
+    pub backoff_ms: u64,
+    pub timeout_ms: u64,
+    // Deliveries that ran out of retries are appended here, one JSON object
+    // per line.
+    pub dead_letter: String,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> NotifyConfig {
+        NotifyConfig {
+            webhooks: Vec::new(),
+            retries: 5,
+            backoff_ms: 1000,
+            timeout_ms: 10_000,
+            dead_letter: "dead_letters.ndjson".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    // Signs the body with HMAC-SHA256 when set.
+    pub secret: Option<String>,
+    // The sources to report on; all of them if empty.
+    #[serde(default)]
+    pub sources: Vec<String>,
+}
+
+const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+pub struct Notifier {
+    client: reqwest::Client,
+    config: NotifyConfig,
+}
+
+// "sha256=" and the hex HMAC of the body, as GitHub does it.
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+impl Notifier {
+    pub fn new(config: &NotifyConfig) -> Result<Notifier, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::builder()
+            .user_agent(crate::http::USER_AGENT)
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()?;
+        Ok(Notifier { client, config: config.clone() })
+    }
+
+    // Sends the payload to every webhook that wants this source, each in the
+    // background so a slow receiver holds up neither the job nor the others.
+    pub fn notify(notifier: &web::Data<Notifier>, source: &str, payload: serde_json::Value) {
+        let body = payload.to_string();
+        for webhook in &notifier.config.webhooks {
+            if !webhook.sources.is_empty() && !webhook.sources.iter().any(|s| s == source) {
+                continue;
+            }
+            let (notifier, webhook, body) = (notifier.clone(), webhook.clone(), body.clone());
+            actix_web::rt::spawn(async move { notifier.deliver(&webhook, &body).await });
+        }
+    }
+
+    async fn deliver(&self, webhook: &Webhook, body: &str) {
+        let mut attempt = 0;
+        loop {
+            let mut request = self.client.post(&webhook.url).header("Content-Type", "application/json").body(body.to_string());
+            if let Some(secret) = &webhook.secret {
+                request = request.header(SIGNATURE_HEADER, sign(secret, body));
+            }
+            let error = match request.send().await {
+                Ok(resp) if resp.status().is_success() => return,
+                Ok(resp) => format!("{} returned {}", webhook.url, resp.status()),
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.config.retries {
+                eprintln!("webhook {}: giving up: {}", webhook.url, error);
+                self.dead_letter(webhook, body, &error);
+                return;
+            }
+            let wait = Duration::from_millis(self.config.backoff_ms.saturating_mul(1 << attempt.min(16)));
+            eprintln!("webhook {}: {}; retrying in {:?}", webhook.url, error, wait);
+            actix_web::rt::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    fn dead_letter(&self, webhook: &Webhook, body: &str, error: &str) {
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+        let line = json!({ "at": store::timestamp(Utc::now()), "url": webhook.url, "error": error, "payload": payload });
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.dead_letter)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(e) = written {
+            eprintln!("dead letter {}: {}", self.config.dead_letter, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    struct Received {
+        headers: Vec<String>,
+        body: String,
+    }
+
+    // A receiver on a free local port that answers every request with
+    // `status`, and hands back what it was sent.
+    fn receiver(status: &str) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::channel();
+        let status = status.to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim_end().is_empty() {
+                        break;
+                    }
+                    headers.push(line.trim_end().to_string());
+                }
+                let length = headers.iter().find_map(|h| h.to_ascii_lowercase().strip_prefix("content-length: ").map(|n| n.parse().unwrap())).unwrap_or(0);
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+                if sender.send(Received { headers, body: String::from_utf8(body).unwrap() }).is_err() {
+                    return;
+                }
+            }
+        });
+        (url, received)
+    }
+
+    fn notifier(dead_letter: &str) -> Notifier {
+        let config = NotifyConfig { retries: 2, backoff_ms: 1, dead_letter: dead_letter.to_string(), ..NotifyConfig::default() };
+        Notifier::new(&config).unwrap()
+    }
+
+    fn header<'a>(received: &'a Received, name: &str) -> Option<&'a str> {
+        let prefix = format!("{}: ", name.to_ascii_lowercase());
+        received.headers.iter().find(|h| h.to_ascii_lowercase().starts_with(&prefix)).map(|h| &h[prefix.len()..])
+    }
+
+    #[tokio::test]
+    async fn posts_signed_json() {
+        let (url, received) = receiver("200 OK");
+        let webhook = Webhook { url, secret: Some("hunter2".to_string()), sources: Vec::new() };
+        let body = r#"{"source":"news","new":[{"title":"Hello"}]}"#;
+        notifier("unused.ndjson").deliver(&webhook, body).await;
+
+        let request = received.recv().unwrap();
+        assert!(request.headers[0].starts_with("POST /hook "));
+        assert_eq!(header(&request, "Content-Type"), Some("application/json"));
+        assert_eq!(header(&request, SIGNATURE_HEADER), Some("sha256=65fe683e806c31aab619ba80edb6ee277538cd9a8288079e93bf61025557ec1a"));
+        let payload: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(payload["new"][0]["title"], "Hello");
+        assert!(received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_then_dead_lettered() {
+        let (url, received) = receiver("503 Service Unavailable");
+        let dead_letter = std::env::temp_dir().join(format!("dead_letters_{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&dead_letter);
+        let webhook = Webhook { url: url.clone(), secret: None, sources: Vec::new() };
+        let body = json!({ "source": "news" }).to_string();
+        notifier(dead_letter.to_str().unwrap()).deliver(&webhook, &body).await;
+
+        for _ in 0..3 {
+            let request = received.recv().unwrap();
+            assert_eq!(request.body, body);
+            assert_eq!(header(&request, SIGNATURE_HEADER), None);
+        }
+        assert!(received.try_recv().is_err());
+
+        let written = std::fs::read_to_string(&dead_letter).unwrap();
+        std::fs::remove_file(&dead_letter).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["url"], url.as_str());
+        assert_eq!(line["payload"]["source"], "news");
+        assert!(line["error"].as_str().unwrap().contains("503"));
+    }
+}
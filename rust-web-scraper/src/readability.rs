@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+
+use ego_tree::NodeId;
+use regex::Regex;
+use scraper::node::Node;
+use scraper::{ElementRef, Html};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::metadata;
+use crate::schema::parse_selector;
+use crate::urls;
+
+// The main text of a page, as plain text and as HTML that is safe to show.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Content {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub text: String,
+    pub html: String,
+    pub word_count: usize,
+    pub reading_time_minutes: usize,
+}
+
+const WORDS_PER_MINUTE: usize = 230;
+
+// Never part of the article, whatever they contain.
+const BOILERPLATE: [&str; 21] = [
+    "script", "style", "noscript", "template", "iframe", "object", "embed", "svg", "canvas", "form", "button", "input", "select",
+    "textarea", "nav", "aside", "footer", "header", "menu", "dialog", "link",
+];
+
+// Kept in the HTML; anything else is replaced by what it contains.
+const ALLOWED: [&str; 37] = [
+    "p", "h1", "h2", "h3", "h4", "h5", "h6", "ul", "ol", "li", "dl", "dt", "dd", "blockquote", "pre", "code", "em", "strong", "b", "i",
+    "u", "s", "sub", "sup", "a", "img", "figure", "figcaption", "br", "hr", "table", "thead", "tbody", "tfoot", "tr", "th", "td",
+];
+
+// Start a new paragraph in the text.
+const BLOCKS: [&str; 26] = [
+    "p", "div", "section", "article", "main", "h1", "h2", "h3", "h4", "h5", "h6", "ul", "ol", "li", "dl", "dt", "dd", "blockquote",
+    "pre", "figure", "figcaption", "table", "tr", "br", "hr", "address",
+];
+
+// Scored as paragraphs: their text counts towards their ancestors.
+const PARAGRAPHS: [&str; 4] = ["p", "pre", "td", "blockquote"];
+
+// Containers that are dropped if they look like a list of links or are empty.
+const CONDITIONAL: [&str; 7] = ["div", "section", "ul", "ol", "table", "dl", "figure"];
+
+// Class and id patterns, after Mozilla's Readability.
+struct Patterns {
+    unlikely: Regex,
+    maybe: Regex,
+    positive: Regex,
+    negative: Regex,
+}
+
+impl Patterns {
+    fn new() -> Patterns {
+        let compile = |pattern| Regex::new(pattern).unwrap();
+        Patterns {
+            unlikely: compile(r"(?i)-ad-|\bads?\b|advert|banner|breadcrumb|combx|comment|community|cookie|disqus|extra|footer|gdpr|legends|menu|modal|nav|newsletter|outbrain|pager|pagination|popup|promo|related|remark|replies|rss|share|shoutbox|sidebar|skyscraper|social|sponsor|subscribe|taboola|widget"),
+            maybe: compile(r"(?i)article|body|column|content|main|shadow"),
+            positive: compile(r"(?i)article|body|content|entry|hentry|h-entry|main|page|post|text|blog|story"),
+            negative: compile(r"(?i)-ad-|hidden|^hid$|banner|combx|comment|com-|contact|foot|footnote|gdpr|masthead|media|meta|outbrain|promo|related|scroll|share|shoutbox|sidebar|skyscraper|sponsor|shopping|tags|tool|widget"),
+        }
+    }
+
+    fn names(element: ElementRef) -> String {
+        let e = element.value();
+        format!("{} {}", e.attr("class").unwrap_or(""), e.id().unwrap_or(""))
+    }
+
+    // Navigation, ads, share buttons and the like, or anything hidden.
+    fn is_boilerplate(&self, element: ElementRef) -> bool {
+        let e = element.value();
+        let name = e.name();
+        if BOILERPLATE.contains(&name) || e.attr("hidden").is_some() || e.attr("aria-hidden") == Some("true") {
+            return true;
+        }
+        if matches!(e.attr("role"), Some("navigation" | "complementary" | "banner" | "contentinfo" | "menu" | "dialog")) {
+            return true;
+        }
+        if e.attr("style").map(|s| s.replace(' ', "").contains("display:none")).unwrap_or(false) {
+            return true;
+        }
+        let names = Patterns::names(element);
+        name != "body" && name != "article" && self.unlikely.is_match(&names) && !self.maybe.is_match(&names)
+    }
+
+    fn weight(&self, element: ElementRef) -> f64 {
+        let e = element.value();
+        [e.attr("class"), e.id()]
+            .into_iter()
+            .flatten()
+            .map(|s| if self.negative.is_match(s) { -25.0 } else { 0.0 } + if self.positive.is_match(s) { 25.0 } else { 0.0 })
+            .sum()
+    }
+}
+
+fn text(element: ElementRef) -> String {
+    element.text().collect::<Vec<_>>().join(" ").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Counts over an element's text, as `text` would give it.
+#[derive(Clone, Copy, Default)]
+struct Stats {
+    words: usize,
+    // Of the words, not counting the spaces between them.
+    chars: usize,
+    commas: usize,
+    // Text within links, spaces included.
+    linked: usize,
+    images: usize,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        self.words += other.words;
+        self.chars += other.chars;
+        self.commas += other.commas;
+        self.linked += other.linked;
+        self.images += other.images;
+    }
+
+    fn length(&self) -> usize {
+        self.chars + self.words.saturating_sub(1)
+    }
+
+    // How much of the text is in links.
+    fn link_density(&self) -> f64 {
+        match self.length() {
+            0 => 0.0,
+            length => self.linked as f64 / length as f64,
+        }
+    }
+}
+
+// Stats for every element under `root`, summed up from the leaves in one
+// pass so that nothing is counted again for each of its ancestors.
+fn stats(root: ElementRef) -> HashMap<NodeId, Stats> {
+    let mut all: HashMap<NodeId, Stats> = HashMap::new();
+    let nodes: Vec<_> = root.descendants().collect();
+    for node in nodes.into_iter().rev() {
+        let mut stats = Stats::default();
+        match node.value() {
+            Node::Text(t) => {
+                for word in t.split_whitespace() {
+                    stats.words += 1;
+                    stats.chars += word.len();
+                }
+                stats.commas = t.matches(',').count();
+            }
+            Node::Element(e) => {
+                for child in node.children() {
+                    if let Some(child) = all.get(&child.id()) {
+                        stats.add(child);
+                    }
+                }
+                match e.name() {
+                    "a" => stats.linked = stats.length(),
+                    "img" => stats.images += 1,
+                    _ => (),
+                }
+            }
+            _ => continue,
+        }
+        all.insert(node.id(), stats);
+    }
+    all
+}
+
+fn has_blocks(element: ElementRef) -> bool {
+    element.children().filter_map(ElementRef::wrap).any(|c| BLOCKS.contains(&c.value().name()) && c.value().name() != "br")
+}
+
+fn tag_score(element: ElementRef) -> f64 {
+    match element.value().name() {
+        "article" | "main" => 10.0,
+        "div" => 5.0,
+        "pre" | "td" | "blockquote" => 3.0,
+        "address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" | "form" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    }
+}
+
+// The paragraphs worth scoring, leaving out whatever is boilerplate. A div
+// with no block children is a paragraph too.
+fn paragraphs<'a>(element: ElementRef<'a>, patterns: &Patterns, found: &mut Vec<ElementRef<'a>>) {
+    for child in element.children().filter_map(ElementRef::wrap) {
+        if patterns.is_boilerplate(child) {
+            continue;
+        }
+        let name = child.value().name();
+        if PARAGRAPHS.contains(&name) || (name == "div" && !has_blocks(child)) {
+            found.push(child);
+        } else {
+            paragraphs(child, patterns, found);
+        }
+    }
+}
+
+// Each paragraph scores for its commas and length, and gives that to its
+// parent in full and to the ancestors above in shrinking parts. The container
+// with the most, less what of it is links, holds the article.
+fn candidates<'a>(body: ElementRef<'a>, patterns: &Patterns, stats: &HashMap<NodeId, Stats>) -> Vec<(ElementRef<'a>, f64)> {
+    let mut found = Vec::new();
+    paragraphs(body, patterns, &mut found);
+    let mut scores: HashMap<NodeId, (ElementRef, f64)> = HashMap::new();
+    for paragraph in found {
+        let counts = stats[&paragraph.id()];
+        if counts.length() < 25 {
+            continue;
+        }
+        let score = 1.0 + counts.commas as f64 + (counts.length() / 100).min(3) as f64;
+        let ancestors = paragraph.ancestors().filter_map(ElementRef::wrap).take(5);
+        for (level, ancestor) in ancestors.enumerate() {
+            if ancestor.value().name() == "html" {
+                break;
+            }
+            let divider = match level {
+                0 => 1.0,
+                1 => 2.0,
+                _ => level as f64 * 3.0,
+            };
+            scores.entry(ancestor.id()).or_insert_with(|| (ancestor, tag_score(ancestor) + patterns.weight(ancestor))).1 += score / divider;
+        }
+    }
+    scores.into_values().map(|(element, score)| (element, score * (1.0 - stats[&element.id()].link_density()))).collect()
+}
+
+// Writes out the kept parts of the article, once as HTML with only safe tags
+// and attributes, and once as text.
+struct Writer<'a> {
+    base: &'a Url,
+    patterns: &'a Patterns,
+    stats: &'a HashMap<NodeId, Stats>,
+    title: Option<&'a str>,
+    html: String,
+    text: String,
+}
+
+fn escape(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+impl Writer<'_> {
+    // Lists of links, share bars and empty wrappers that made it into the
+    // article's container.
+    fn is_clutter(&self, element: ElementRef) -> bool {
+        let name = element.value().name();
+        if !CONDITIONAL.contains(&name) {
+            return false;
+        }
+        let weight = self.patterns.weight(element);
+        if weight < 0.0 {
+            return true;
+        }
+        let stats = &self.stats[&element.id()];
+        if stats.length() == 0 {
+            return stats.images == 0;
+        }
+        if stats.commas >= 10 {
+            return false;
+        }
+        let density = stats.link_density();
+        (weight < 25.0 && density > 0.2 && name != "ul" && name != "ol") || density > 0.5
+    }
+
+    fn attr(&mut self, name: &str, value: &str) {
+        self.html.push(' ');
+        self.html.push_str(name);
+        self.html.push_str("=\"");
+        escape(value, &mut self.html);
+        self.html.push('"');
+    }
+
+    fn url(&self, value: Option<&str>, schemes: &[&str]) -> Option<Url> {
+        urls::resolve(self.base, value?).filter(|u| schemes.contains(&u.scheme()))
+    }
+
+    fn children(&mut self, element: ElementRef) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(t) => {
+                    escape(t, &mut self.html);
+                    self.text.push_str(t);
+                }
+                Node::Element(_) => self.element(ElementRef::wrap(child).unwrap()),
+                _ => (),
+            }
+        }
+    }
+
+    fn element(&mut self, element: ElementRef) {
+        if self.patterns.is_boilerplate(element) || self.is_clutter(element) {
+            return;
+        }
+        let e = element.value();
+        let name = e.name();
+        // The title is reported on its own.
+        if matches!(name, "h1" | "h2") && self.title.is_some() && Some(text(element).as_str()) == self.title {
+            return;
+        }
+        let block = BLOCKS.contains(&name);
+        if block {
+            self.text.push_str("\n\n");
+        }
+        if !ALLOWED.contains(&name) {
+            self.children(element);
+        } else if name == "img" {
+            if let Some(src) = self.url(e.attr("src").or_else(|| e.attr("data-src")), &["http", "https"]) {
+                self.html.push_str("<img");
+                self.attr("src", src.as_str());
+                if let Some(alt) = e.attr("alt") {
+                    self.attr("alt", alt);
+                }
+                self.html.push('>');
+            }
+        } else {
+            self.html.push('<');
+            self.html.push_str(name);
+            match name {
+                "a" => {
+                    if let Some(href) = self.url(e.attr("href"), &["http", "https", "mailto"]) {
+                        self.attr("href", href.as_str());
+                    }
+                }
+                "td" | "th" => {
+                    for span in ["colspan", "rowspan"] {
+                        if let Some(value) = e.attr(span).filter(|v| v.chars().all(|c| c.is_ascii_digit())) {
+                            self.attr(span, value);
+                        }
+                    }
+                }
+                _ => (),
+            }
+            self.html.push('>');
+            if name != "br" && name != "hr" {
+                self.children(element);
+                self.html.push_str("</");
+                self.html.push_str(name);
+                self.html.push('>');
+            }
+        }
+        if block {
+            self.text.push_str("\n\n");
+        }
+    }
+}
+
+fn title(document: &Html) -> Option<String> {
+    let first = |selector: &str| document.select(&parse_selector(selector).unwrap()).next().map(text).filter(|t| !t.is_empty());
+    metadata::meta(document, &["og:title", "twitter:title"]).or_else(|| first("h1")).or_else(|| first("title"))
+}
+
+// The page's article, readability-style: the best scoring container, with
+// those of its siblings that look like they belong to it, cleaned of
+// navigation, ads and other boilerplate. None if nothing looks like text.
+pub fn extract(document: &Html, page: &Url) -> Option<Content> {
+    let patterns = Patterns::new();
+    let base = urls::base(document, page);
+    let body = document.select(&parse_selector("body").unwrap()).next().unwrap_or_else(|| document.root_element());
+    let stats = stats(body);
+    let candidates = candidates(body, &patterns, &stats);
+    let (top, top_score) = candidates.iter().copied().max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+    // Articles are often split over siblings, e.g. a lead and the body.
+    let threshold = (top_score * 0.2).max(10.0);
+    let score = |element: ElementRef| candidates.iter().find(|(c, _)| c.id() == element.id()).map(|(_, s)| *s);
+    let parts: Vec<ElementRef> = match top.parent() {
+        Some(parent) if top.value().name() != "body" => parent
+            .children()
+            .filter_map(ElementRef::wrap)
+            .filter(|sibling| {
+                if sibling.id() == top.id() {
+                    return true;
+                }
+                if score(*sibling).map(|s| s >= threshold).unwrap_or(false) {
+                    return true;
+                }
+                if sibling.value().name() != "p" || patterns.is_boilerplate(*sibling) {
+                    return false;
+                }
+                let (text, density) = (text(*sibling), stats[&sibling.id()].link_density());
+                (text.len() > 80 && density < 0.25) || (density == 0.0 && text.contains(". "))
+            })
+            .collect(),
+        _ => vec![top],
+    };
+
+    let title = title(document);
+    let mut writer = Writer { base: &base, patterns: &patterns, stats: &stats, title: title.as_deref(), html: String::new(), text: String::new() };
+    for part in parts {
+        writer.element(part);
+    }
+    let html = writer.html;
+    let text = writer
+        .text
+        .split("\n\n")
+        .map(|p| p.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if text.is_empty() {
+        return None;
+    }
+    let word_count = text.split_whitespace().count();
+    Some(Content {
+        title,
+        text,
+        html,
+        word_count,
+        reading_time_minutes: word_count.div_ceil(WORDS_PER_MINUTE),
+    })
+}
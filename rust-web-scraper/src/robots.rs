@@ -0,0 +1,174 @@
+use std::time::Duration;
+
+// The rules from a robots.txt that apply to one user agent.
+#[derive(Default)]
+pub struct Robots {
+    rules: Vec<(bool, String)>,
+    pub crawl_delay: Option<Duration>,
+}
+
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<(bool, String)>,
+    crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    // Uses the group naming our user agent if there is one, otherwise the
+    // `*` group. Unknown lines are ignored, as robots.txt files are often
+    // sloppy.
+    pub fn parse(text: &str, user_agent: &str) -> Robots {
+        let mut groups: Vec<Group> = Vec::new();
+        let mut in_agents = false;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let (key, value) = match line.split_once(':') {
+                Some((k, v)) => (k.trim().to_lowercase(), v.trim()),
+                None => continue,
+            };
+            if key == "user-agent" {
+                if !in_agents {
+                    groups.push(Group { agents: Vec::new(), rules: Vec::new(), crawl_delay: None });
+                }
+                in_agents = true;
+                if let Some(g) = groups.last_mut() {
+                    g.agents.push(value.to_lowercase());
+                }
+                continue;
+            }
+            in_agents = false;
+            let group = match groups.last_mut() {
+                Some(g) => g,
+                None => continue,
+            };
+            match key.as_str() {
+                "allow" => group.rules.push((true, value.to_string())),
+                // An empty Disallow allows everything.
+                "disallow" if !value.is_empty() => group.rules.push((false, value.to_string())),
+                "crawl-delay" => {
+                    group.crawl_delay = value.parse::<f64>().ok().filter(|d| *d >= 0.0).map(Duration::from_secs_f64);
+                }
+                _ => (),
+            }
+        }
+
+        let agent = user_agent.to_lowercase();
+        let group = groups
+            .iter()
+            .find(|g| g.agents.iter().any(|a| a != "*" && agent.contains(a.as_str())))
+            .or_else(|| groups.iter().find(|g| g.agents.iter().any(|a| a == "*")));
+        match group {
+            Some(g) => Robots { rules: g.rules.clone(), crawl_delay: g.crawl_delay },
+            None => Robots::default(),
+        }
+    }
+
+    // The longest matching rule wins, and Allow wins a tie.
+    pub fn allowed(&self, path: &str) -> bool {
+        let mut best: Option<(usize, bool)> = None;
+        for (allow, pattern) in &self.rules {
+            if !matches(pattern, path) {
+                continue;
+            }
+            let len = pattern.len();
+            best = match best {
+                Some((l, a)) if l > len || (l == len && a) => Some((l, a)),
+                _ => Some((len, *allow)),
+            };
+        }
+        best.map(|(_, allow)| allow).unwrap_or(true)
+    }
+}
+
+// Patterns match path prefixes, with `*` for any run of characters and a
+// trailing `$` to anchor the end.
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(p) => (p, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !path.starts_with(first) {
+        return false;
+    }
+    let mut rest = &path[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        // The last piece of an anchored pattern has to match at the very end.
+        if anchored && i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        let cases = [
+            ("/fish", "/fish", true),
+            ("/fish", "/fish.html", true),
+            ("/fish", "/Fish.html", false),
+            ("/fish", "/catfish", false),
+            ("/fish*", "/fishheads/yummy.html", true),
+            ("/*.php", "/index.php", true),
+            ("/*.php", "/folder/any.php.file.html", true),
+            ("/*.php", "/windows.PHP", false),
+            ("/*.php$", "/filename.php", true),
+            ("/*.php$", "/filename.php?parameters", false),
+            ("/*.php$", "/filename.php/", false),
+            ("/a*b*c", "/axxbyyc/z", true),
+            ("/a*b*c", "/axxcyyb", false),
+            ("/a*b$", "/ab", true),
+            ("/a*a$", "/a", false),
+            ("/$", "/", true),
+            ("/$", "/page", false),
+            ("*", "/anything", true),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(matches(pattern, path), expected, "{} against {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn longest_match_wins() {
+        let cases = [
+            ("Allow: /p\nDisallow: /", "/page", true),
+            ("Allow: /folder\nDisallow: /folder", "/folder/page", true),
+            ("Allow: /page\nDisallow: /*.htm", "/page.htm", false),
+            ("Allow: /$\nDisallow: /", "/", true),
+            ("Allow: /$\nDisallow: /", "/page.htm", false),
+            ("Disallow: /private/\nAllow: /private/public/", "/private/public/a", true),
+            ("Disallow: /private/\nAllow: /private/public/", "/private/a", false),
+            ("Disallow: /*.gif$", "/images/a.gif", false),
+            ("Disallow: /*.gif$", "/images/a.gif.html", true),
+            ("Disallow:", "/anything", true),
+        ];
+        for (rules, path, expected) in cases {
+            let robots = Robots::parse(&format!("User-agent: *\n{}", rules), "scraper");
+            assert_eq!(robots.allowed(path), expected, "{:?} for {}", rules, path);
+        }
+    }
+
+    #[test]
+    fn our_group_is_preferred_to_the_default() {
+        let text = "User-agent: *\nDisallow: /\n\nUser-agent: other\nUser-agent: Scraper # us\nDisallow: /private\nCrawl-delay: 1.5\n";
+        let robots = Robots::parse(text, "scraper/0.1");
+        assert!(robots.allowed("/public"));
+        assert!(!robots.allowed("/private/a"));
+        assert_eq!(robots.crawl_delay, Some(Duration::from_millis(1500)));
+
+        let robots = Robots::parse(text, "somebot");
+        assert!(!robots.allowed("/public"));
+        assert_eq!(robots.crawl_delay, None);
+        assert!(Robots::parse("", "scraper").allowed("/"));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use regex::Regex;
+use scraper::{ElementRef, Selector};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use url::Url;
+
+use crate::error::Error;
+use crate::urls;
+
+// A field is either just a selector, whose text is taken as a string, or a
+// table spelling out how to extract and convert it.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum FieldSpec {
+    Selector(String),
+    Field(Field),
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Field {
+    // Without a selector the field is read from the item element itself.
+    pub selector: Option<String>,
+    pub extract: Option<Extract>,
+    pub attr: Option<String>,
+    pub regex: Option<String>,
+    #[serde(default, rename = "type")]
+    pub kind: FieldType,
+    // Items missing a required field are dropped.
+    #[serde(default)]
+    pub required: bool,
+    // Sub-fields turn the field into a record, or a list of records.
+    pub fields: Option<BTreeMap<String, FieldSpec>>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Extract {
+    Text,
+    Html,
+    Attr,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    #[default]
+    String,
+    Int,
+    Float,
+    Date,
+    Url,
+    List,
+}
+
+struct Compiled {
+    name: String,
+    selector: Option<Selector>,
+    extract: Extract,
+    attr: Option<String>,
+    regex: Option<Regex>,
+    kind: FieldType,
+    required: bool,
+    fields: Option<Schema>,
+}
+
+// A set of fields with their selectors and regexes parsed, ready to run
+// against a page.
+pub struct Schema {
+    fields: Vec<Compiled>,
+}
+
+pub fn parse_selector(s: &str) -> Result<Selector, Error> {
+    Selector::parse(s).map_err(|e| Error::Selector(format!("invalid selector {:?}: {:?}", s, e.kind)))
+}
+
+impl Schema {
+    pub fn compile(fields: &BTreeMap<String, FieldSpec>) -> Result<Schema, Error> {
+        let mut compiled = Vec::new();
+        for (name, spec) in fields {
+            let field = match spec {
+                FieldSpec::Selector(s) => Field {
+                    selector: Some(s.clone()),
+                    ..Field::default()
+                },
+                FieldSpec::Field(f) => f.clone(),
+            };
+            let extract = match (field.extract, &field.attr) {
+                (Some(Extract::Attr), None) => {
+                    return Err(Error::Parse(format!("field {}: extract = \"attr\" needs an attr", name)));
+                }
+                (Some(e), _) => e,
+                (None, Some(_)) => Extract::Attr,
+                (None, None) => Extract::Text,
+            };
+            let selector = match &field.selector {
+                Some(s) => Some(parse_selector(s).map_err(|e| e.context(format!("field {}", name)))?),
+                None => None,
+            };
+            let regex = match &field.regex {
+                Some(r) => Some(Regex::new(r).map_err(|e| Error::from(e).context(format!("field {}", name)))?),
+                None => None,
+            };
+            let sub = match &field.fields {
+                Some(f) => Some(Schema::compile(f).map_err(|e| e.context(format!("field {}", name)))?),
+                None => None,
+            };
+            compiled.push(Compiled {
+                name: name.clone(),
+                selector,
+                extract,
+                attr: field.attr,
+                regex,
+                kind: field.kind,
+                required: field.required,
+                fields: sub,
+            });
+        }
+        Ok(Schema { fields: compiled })
+    }
+
+    // Returns None when a required field is missing. URLs are resolved against
+    // `base` and normalised.
+    pub fn extract(&self, element: ElementRef, base: &Url) -> Option<Map<String, Value>> {
+        let mut record = Map::new();
+        for field in &self.fields {
+            let value = field.value(element, base);
+            let missing = match &value {
+                Value::Null => true,
+                Value::Array(a) => a.is_empty(),
+                _ => false,
+            };
+            if missing && field.required {
+                return None;
+            }
+            record.insert(field.name.clone(), value);
+        }
+        Some(record)
+    }
+}
+
+impl Compiled {
+    fn value(&self, element: ElementRef, base: &Url) -> Value {
+        let mut matches: Vec<ElementRef> = match &self.selector {
+            Some(s) => element.select(s).collect(),
+            None => vec![element],
+        };
+        if self.kind != FieldType::List {
+            matches.truncate(1);
+        }
+
+        let values: Vec<Value> = matches
+            .into_iter()
+            .filter_map(|e| match &self.fields {
+                Some(schema) => schema.extract(e, base).map(Value::Object),
+                None => self.convert(&self.raw(e)?, base),
+            })
+            .collect();
+
+        if self.kind == FieldType::List {
+            Value::Array(values)
+        } else {
+            values.into_iter().next().unwrap_or(Value::Null)
+        }
+    }
+
+    fn raw(&self, element: ElementRef) -> Option<String> {
+        let raw = match self.extract {
+            Extract::Text => element.text().collect::<String>().split_whitespace().collect::<Vec<_>>().join(" "),
+            Extract::Html => element.inner_html().trim().to_string(),
+            Extract::Attr => element.value().attr(self.attr.as_deref()?)?.trim().to_string(),
+        };
+        match &self.regex {
+            // The first capture group if there is one, otherwise the whole match.
+            Some(re) => {
+                let caps = re.captures(&raw)?;
+                Some(caps.get(1).or_else(|| caps.get(0))?.as_str().to_string())
+            }
+            None => Some(raw),
+        }
+    }
+
+    fn convert(&self, raw: &str, base: &Url) -> Option<Value> {
+        match self.kind {
+            FieldType::String | FieldType::List => Some(Value::String(raw.to_string())),
+            FieldType::Int => {
+                let n = number(raw)?;
+                n.parse::<i64>().ok().or_else(|| n.parse::<f64>().ok().map(|f| f as i64)).map(Value::from)
+            }
+            FieldType::Float => number(raw)?.parse::<f64>().ok().map(Value::from),
+            FieldType::Date => parse_date(raw).map(Value::String),
+            FieldType::Url => urls::resolve(base, raw).map(|u| Value::String(u.to_string())),
+        }
+    }
+}
+
+// Drops currency signs, thousands separators and the like.
+fn number(raw: &str) -> Option<String> {
+    let n: String = raw.chars().filter(|c| c.is_ascii_digit() || *c == '.' || *c == '-').collect();
+    if n.is_empty() {
+        None
+    } else {
+        Some(n)
+    }
+}
+
+const DATE_FORMATS: [&str; 6] = ["%Y-%m-%d", "%d/%m/%Y", "%B %d, %Y", "%b %d, %Y", "%d %B %Y", "%d %b %Y"];
+const DATETIME_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"];
+
+// Dates come out as YYYY-MM-DD, and times as RFC 3339.
+pub fn parse_date(raw: &str) -> Option<String> {
+    if let Ok(d) = DateTime::parse_from_rfc3339(raw) {
+        return Some(d.to_rfc3339());
+    }
+    if let Ok(d) = DateTime::parse_from_rfc2822(raw) {
+        return Some(d.to_rfc3339());
+    }
+    for f in DATETIME_FORMATS {
+        if let Ok(d) = NaiveDateTime::parse_from_str(raw, f) {
+            return Some(d.and_utc().to_rfc3339());
+        }
+    }
+    for f in DATE_FORMATS {
+        if let Ok(d) = NaiveDate::parse_from_str(raw, f) {
+            return Some(d.format("%Y-%m-%d").to_string());
+        }
+    }
+    None
+}
@@ -1,15 +1,168 @@
-use reqwest;
-use scraper::{Html, Selector};
+use std::collections::HashSet;
+
+use scraper::Html;
+use serde_json::Value;
+use url::Url;
+use crate::cache::{Cache, Tally};
+use crate::config::Source;
+use crate::crawler;
+use crate::error::Error;
+use crate::extractors::Extraction;
+use crate::http::Http;
+use crate::metadata::{self, Mode};
 use crate::models::Article;
- 
-pub async fn scrape_articles() -> Result<Vec<Article>, Box<dyn std::error::Error>> {
-    let resp = reqwest::get("https://example.com").await?.text().await?;
+use crate::readability;
+use crate::urls;
+
+// Runs whichever extractor reads this page. Items whose key is already in
+// `seen` are skipped; URL fields are normalised, so this catches the same link
+// written different ways.
+pub fn extract(document: &Html, page: &Url, extraction: &Extraction, source: &Source, seen: &mut HashSet<String>) -> Vec<Article> {
+    let extractor = match extraction.extractor(page) {
+        Some(extractor) => extractor,
+        None => return Vec::new(),
+    };
+    let mut articles = extractor.extract(document, page);
+    dedup(&mut articles, &source.key, seen);
+    if source.metadata == Some(Mode::Page) {
+        metadata::from_listing(document, &urls::base(document, page), &source.key, &mut articles);
+    }
+    articles
+}
+
+// Drops the items whose key is already in `seen`, and adds the others'.
+fn dedup(articles: &mut Vec<Article>, key: &str, seen: &mut HashSet<String>) {
+    articles.retain(|article| match article.fields.get(key).and_then(|v| v.as_str()) {
+        Some(key) => seen.insert(key.to_string()),
+        None => true,
+    });
+}
+
+pub async fn scrape_articles(http: &Http, cache: &Cache, source: &Source, tally: &mut Tally) -> Result<Vec<Article>, Error> {
+    let mut articles = match &source.crawl {
+        Some(crawl) => crawler::crawl(http, cache, source, crawl, tally).await?,
+        None => scrape_page(http, cache, source, tally).await?,
+    };
+    if source.metadata == Some(Mode::Article) || source.content {
+        visit_articles(http, cache, source, &mut articles, tally).await;
+    }
+    Ok(articles)
+}
+
+// Fetches each article's own page for its metadata or text, whichever the
+// source wants. One that can't be fetched keeps what the listing gave it.
+// The page's canonical URL replaces the link it was reached through, so
+// items that turn out to be the same article are dropped after the first.
+async fn visit_articles(http: &Http, cache: &Cache, source: &Source, articles: &mut Vec<Article>, tally: &mut Tally) {
+    for article in articles.iter_mut() {
+        let link = match metadata::article_url(article, &source.key) {
+            Some(link) => link,
+            None => continue,
+        };
+        let url = match Url::parse(&link) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+            _ => continue,
+        };
+        let body = match cache.fetch(http, &source.name, &url, tally).await {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("{} {}: {}", source.name, url, e);
+                continue;
+            }
+        };
+        let document = Html::parse_document(&body);
+        if let Some(canonical) = urls::canonical(&document, &url) {
+            for value in article.fields.values_mut() {
+                if value.as_str() == Some(link.as_str()) {
+                    *value = Value::from(canonical.as_str());
+                }
+            }
+        }
+        if source.metadata == Some(Mode::Article) {
+            metadata::document(&document, &url).apply(article);
+        }
+        if source.content {
+            article.content = readability::extract(&document, &url);
+        }
+    }
+    dedup(articles, &source.key, &mut HashSet::new());
+}
+
+async fn scrape_page(http: &Http, cache: &Cache, source: &Source, tally: &mut Tally) -> Result<Vec<Article>, Error> {
+    let extraction = Extraction::for_source(source)?;
+    let page = Url::parse(&source.url)?;
+
+    let resp = cache.fetch(http, &source.name, &page, tally).await?;
     let document = Html::parse_document(&resp);
-    let selector = Selector::parse("article").unwrap();
-    let articles = document.select(&selector).filter_map(|article| {
-        let title = article.select(&Selector::parse("h2").unwrap()).next()?.text().collect::<String>();
-        let link = article.select(&Selector::parse("a").unwrap()).next()?.value().attr("href")?.to_string();
-        Some(Article { title, link })
-    }).collect();
+    let articles = extract(&document, &page, &extraction, source, &mut HashSet::new());
+    if articles.is_empty() {
+        return Err(Error::NoItems(page.to_string()));
+    }
     Ok(articles)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheConfig;
+    use crate::http::HttpConfig;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A site on a free local port serving `pages` by path, and 404 for
+    // anything else.
+    fn site(pages: &'static [(&'static str, &'static str)]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request.split_whitespace().nth(1).unwrap_or("");
+                let (status, body) = match pages.iter().find(|(p, _)| *p == path) {
+                    Some((_, body)) => ("200 OK", *body),
+                    None => ("404 Not Found", ""),
+                };
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn articles_move_to_their_canonical_url() {
+        static PAGES: &[(&str, &str)] = &[
+            ("/", r#"<ul><li><a href="/story?ref=home">A</a></li><li><a href="/story?ref=top">A again</a></li><li><a href="/other">B</a></li></ul>"#),
+            ("/story?ref=home", r#"<link rel="canonical" href="/story"><meta name="description" content="The story">"#),
+            ("/story?ref=top", r#"<link rel="canonical" href="/story">"#),
+            ("/other", "<p>No canonical link here.</p>"),
+        ];
+        let base = site(PAGES);
+        let source: Source = toml::from_str(&format!(
+            r#"
+            name = "test"
+            url = "{}/"
+            item = "li"
+            metadata = "article"
+            fields = {{ title = "a", link = {{ selector = "a", attr = "href", type = "url" }} }}
+            "#,
+            base
+        ))
+        .unwrap();
+        let http = Http::new(&HttpConfig::default()).unwrap();
+        let cache = Cache::new(&CacheConfig::default());
+
+        let articles = scrape_articles(&http, &cache, &source, &mut Tally::default()).await.unwrap();
+        let links: Vec<_> = articles.iter().map(|a| a.fields["link"].as_str().unwrap()).collect();
+        assert_eq!(links, [format!("{}/story", base), format!("{}/other", base)]);
+        assert_eq!(articles[0].fields["title"], "A");
+        assert_eq!(articles[0].metadata.description.as_deref(), Some("The story"));
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::Value;
+use tantivy::collector::{Count, DocSetCollector, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{self, AllQuery, BooleanQuery, Occur, QueryParser, RangeQuery, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Value as _, FAST, INDEXED, STORED, STRING, TEXT};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{doc, Index, IndexReader, IndexSettings, IndexWriter, ReloadPolicy, TantivyDocument, TantivyError, Term};
+
+use crate::error::Error;
+use crate::models::StoredArticle;
+use crate::store::{self, Store};
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchConfig {
+    // A directory, created if missing.
+    pub path: String,
+}
+
+impl Default for SearchConfig {
+    fn default() -> SearchConfig {
+        SearchConfig { path: "scraper.index".to_string() }
+    }
+}
+
+const WRITER_MEMORY: usize = 50_000_000;
+const SNIPPET_LENGTH: usize = 200;
+// Articles read from the store at a time when catching up with it.
+const REBUILD_BATCH: usize = 500;
+
+impl From<tantivy::TantivyError> for Error {
+    fn from(e: tantivy::TantivyError) -> Error {
+        Error::Store(format!("search index: {}", e))
+    }
+}
+
+struct Fields {
+    id: Field,
+    source: Field,
+    title: Field,
+    body: Field,
+    date: Field,
+    // The store's content hash for the row, to tell when it has changed.
+    hash: Field,
+}
+
+// A full-text index over the store, pointing back at its rows by id.
+pub struct Search {
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    fields: Fields,
+    // Set when an update failed, so the next one compares the whole store.
+    behind: AtomicBool,
+}
+
+// Filters and paging for `Search::search`.
+pub struct Query<'a> {
+    pub q: &'a str,
+    pub source: Option<&'a str>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+pub struct Hit {
+    pub id: i64,
+    pub score: f32,
+    // Where the query matched, as HTML with the terms in <b>; None where it
+    // didn't.
+    pub title: Option<String>,
+    pub snippet: Option<String>,
+}
+
+fn strings(article: &StoredArticle, names: &[&str]) -> Vec<String> {
+    names
+        .iter()
+        .filter_map(|name| article.article.fields.get(*name).and_then(|v| v.as_str()))
+        .map(String::from)
+        .collect()
+}
+
+// The title as the feeds pick it, and everything else worth finding an
+// article by: its other text fields, metadata and full text. URLs are left out.
+fn text(article: &StoredArticle) -> (String, String) {
+    let title = strings(article, &["title", "name"]).into_iter().next().unwrap_or_default();
+    let a = &article.article;
+    let mut body: Vec<String> = a
+        .fields
+        .iter()
+        .filter_map(|(_, v)| match v {
+            Value::String(s) if *s != title && !s.starts_with("http://") && !s.starts_with("https://") => Some(s.clone()),
+            _ => None,
+        })
+        .collect();
+    let meta = &a.metadata;
+    body.extend([&meta.description, &meta.author, &meta.site_name].into_iter().flatten().cloned());
+    body.extend(a.content.as_ref().map(|c| c.text.clone()));
+    (title, body.join("\n"))
+}
+
+fn seconds(time: &str) -> Option<i64> {
+    let time = store::parse_since(time)?;
+    DateTime::parse_from_rfc3339(&time).ok().map(|t| t.timestamp())
+}
+
+// When the article came out, or when it was first seen if it doesn't say.
+fn date(article: &StoredArticle) -> tantivy::DateTime {
+    let published = strings(article, &["published_at"]).into_iter().next().or_else(|| article.article.metadata.published_at.clone());
+    let published = published.as_deref().and_then(seconds);
+    let seconds = published.or_else(|| seconds(&article.first_seen)).unwrap_or(0);
+    tantivy::DateTime::from_timestamp_secs(seconds)
+}
+
+impl Search {
+    pub fn open(config: &SearchConfig) -> Result<Search, Error> {
+        let mut schema = Schema::builder();
+        let fields = Fields {
+            id: schema.add_i64_field("id", INDEXED | STORED | FAST),
+            source: schema.add_text_field("source", STRING),
+            title: schema.add_text_field("title", TEXT | STORED),
+            body: schema.add_text_field("body", TEXT | STORED),
+            date: schema.add_date_field("date", INDEXED | FAST),
+            hash: schema.add_text_field("hash", STRING | STORED),
+        };
+        let schema = schema.build();
+        fs::create_dir_all(&config.path).map_err(|e| Error::Store(format!("{}: {}", config.path, e)))?;
+        let directory = MmapDirectory::open(&config.path).map_err(|e| Error::Store(format!("{}: {}", config.path, e)))?;
+        // The index only mirrors the store, so one from an older version is
+        // started over and filled in again by `sync`.
+        let index = match Index::open_or_create(directory.clone(), schema.clone()) {
+            Err(TantivyError::SchemaError(_)) => {
+                eprintln!("search: {} has an old schema, recreating it", config.path);
+                Index::create(directory, schema, IndexSettings::default())?
+            }
+            index => index?,
+        };
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
+        let writer = Mutex::new(index.writer(WRITER_MEMORY)?);
+        Ok(Search { index, reader, writer, fields, behind: AtomicBool::new(false) })
+    }
+
+    // Indexes the store's rows with these ids after a save. If an update
+    // failed before, the whole store is compared instead so nothing it missed
+    // stays out of date.
+    pub fn update(&self, store: &Store, ids: &[i64]) -> Result<(), Error> {
+        let result = if self.behind.load(Ordering::Relaxed) {
+            self.sync(store)
+        } else {
+            store.by_id(ids).and_then(|articles| self.index(&articles))
+        };
+        self.behind.store(result.is_err(), Ordering::Relaxed);
+        result
+    }
+
+    // Adds the articles, replacing what was indexed for them before.
+    pub fn index(&self, articles: &[StoredArticle]) -> Result<(), Error> {
+        let mut writer = self.writer.lock().unwrap();
+        for article in articles {
+            self.add(&writer, article)?;
+        }
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    fn add(&self, writer: &IndexWriter, article: &StoredArticle) -> Result<(), Error> {
+        let f = &self.fields;
+        let (title, body) = text(article);
+        writer.delete_term(Term::from_field_i64(f.id, article.id));
+        writer.add_document(doc!(
+            f.id => article.id,
+            f.source => article.source.clone(),
+            f.title => title,
+            f.body => body,
+            f.date => date(article),
+            f.hash => article.hash.clone(),
+        ))?;
+        Ok(())
+    }
+
+    // The id and content hash of every indexed article.
+    fn indexed(&self) -> Result<HashMap<i64, String>, Error> {
+        let f = &self.fields;
+        let searcher = self.reader.searcher();
+        let mut indexed = HashMap::new();
+        for address in searcher.search(&AllQuery, &DocSetCollector)? {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            if let Some(id) = doc.get_first(f.id).and_then(|v| v.as_i64()) {
+                let hash = doc.get_first(f.hash).and_then(|v| v.as_str()).unwrap_or_default();
+                indexed.insert(id, hash.to_string());
+            }
+        }
+        Ok(indexed)
+    }
+
+    // Brings the index in line with the store: indexes the rows it is missing
+    // or holds an older version of, and drops those that are gone. This
+    // covers a new index, a replaced database and updates that failed.
+    pub fn sync(&self, store: &Store) -> Result<(), Error> {
+        let mut indexed = self.indexed()?;
+        let stale: Vec<i64> = store.hashes()?.into_iter().filter(|(id, hash)| indexed.remove(id).as_ref() != Some(hash)).map(|(id, _)| id).collect();
+        if stale.is_empty() && indexed.is_empty() {
+            return Ok(());
+        }
+        eprintln!("search: indexing {} articles, removing {}", stale.len(), indexed.len());
+        let mut writer = self.writer.lock().unwrap();
+        for id in indexed.keys() {
+            writer.delete_term(Term::from_field_i64(self.fields.id, *id));
+        }
+        for ids in stale.chunks(REBUILD_BATCH) {
+            for article in &store.by_id(ids)? {
+                self.add(&writer, article)?;
+            }
+        }
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    // Best match first, with the total number of matches for paging. The
+    // query takes "quoted phrases", +required and -excluded terms, OR, and
+    // title: or body: in front of a term; all terms must match otherwise.
+    pub fn search(&self, query: &Query) -> Result<(Vec<Hit>, usize), Error> {
+        let f = &self.fields;
+        let mut parser = QueryParser::for_index(&self.index, vec![f.title, f.body]);
+        parser.set_conjunction_by_default();
+        parser.set_field_boost(f.title, 2.0);
+        let text = parser.parse_query(query.q).map_err(|e| Error::Parse(format!("invalid query: {}", e)))?;
+
+        let mut clauses: Vec<(Occur, Box<dyn query::Query>)> = vec![(Occur::Must, text.box_clone())];
+        if let Some(source) = query.source {
+            clauses.push((Occur::Must, Box::new(TermQuery::new(Term::from_field_text(f.source, source), IndexRecordOption::Basic))));
+        }
+        if query.since.is_some() || query.until.is_some() {
+            let bound = |time: &Option<String>, bound: fn(tantivy::DateTime) -> Bound<tantivy::DateTime>| match time.as_deref().and_then(seconds) {
+                Some(s) => bound(tantivy::DateTime::from_timestamp_secs(s)),
+                None => Bound::Unbounded,
+            };
+            let range = RangeQuery::new_date_bounds("date".to_string(), bound(&query.since, Bound::Included), bound(&query.until, Bound::Excluded));
+            clauses.push((Occur::Must, Box::new(range)));
+        }
+        let filtered = BooleanQuery::new(clauses);
+
+        let searcher = self.reader.searcher();
+        let collector = (TopDocs::with_limit(query.limit.max(1)).and_offset(query.offset), Count);
+        let (top, total) = searcher.search(&filtered, &collector)?;
+        let snippets = |field| -> Result<SnippetGenerator, Error> {
+            let mut generator = SnippetGenerator::create(&searcher, &*text, field)?;
+            generator.set_max_num_chars(SNIPPET_LENGTH);
+            Ok(generator)
+        };
+        let (titles, bodies) = (snippets(f.title)?, snippets(f.body)?);
+        let mut hits = Vec::new();
+        for (score, address) in top.into_iter().take(query.limit) {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let id = match doc.get_first(f.id).and_then(|v| v.as_i64()) {
+                Some(id) => id,
+                None => continue,
+            };
+            let highlight = |generator: &SnippetGenerator| Some(generator.snippet_from_doc(&doc)).filter(|s| !s.highlighted().is_empty()).map(|s| s.to_html());
+            hits.push(Hit { id, score, title: highlight(&titles), snippet: highlight(&bodies) });
+        }
+        Ok((hits, total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Article;
+    use crate::store::StoreConfig;
+
+    fn article(title: &str, description: &str) -> Article {
+        let mut article = Article::default();
+        article.metadata.description = Some(description.to_string());
+        article.fields.insert("title".to_string(), Value::from(title));
+        article.fields.insert("link".to_string(), Value::from("https://example.com/1"));
+        article
+    }
+
+    fn find(search: &Search, q: &str) -> Vec<i64> {
+        let query = Query { q, source: None, since: None, until: None, limit: 10, offset: 0 };
+        search.search(&query).unwrap().0.into_iter().map(|hit| hit.id).collect()
+    }
+
+    #[test]
+    fn changed_articles_are_reindexed() {
+        let path = std::env::temp_dir().join(format!("scraper_index_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let store = Store::open(&StoreConfig { path: ":memory:".to_string() }).unwrap();
+        let search = Search::open(&SearchConfig { path: path.to_str().unwrap().to_string() }).unwrap();
+
+        let saved = store.save("news", "link", &[article("Volcano erupts", "Ash over the island")]).unwrap();
+        search.update(&store, &saved.ids).unwrap();
+        assert_eq!(find(&search, "ash"), saved.ids);
+
+        // Saved without updating the index, as when indexing failed.
+        store.save("news", "link", &[article("Volcano calms down", "Flights resume")]).unwrap();
+        assert_eq!(find(&search, "flights"), Vec::<i64>::new());
+
+        search.sync(&store).unwrap();
+        assert_eq!(find(&search, "flights"), saved.ids);
+        assert_eq!(find(&search, "ash"), Vec::<i64>::new());
+        assert_eq!(search.reader.searcher().num_docs(), 1);
+
+        drop(search);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
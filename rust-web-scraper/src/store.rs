@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::web;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::models::{Article, StoredArticle};
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    pub path: String,
+}
+
+impl Default for StoreConfig {
+    fn default() -> StoreConfig {
+        StoreConfig { path: "scraper.db".to_string() }
+    }
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS articles (
+    id INTEGER PRIMARY KEY,
+    source TEXT NOT NULL,
+    key TEXT NOT NULL,
+    fields TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    UNIQUE (source, key)
+);
+CREATE INDEX IF NOT EXISTS articles_first_seen ON articles (first_seen);
+";
+
+// Runs store and search index calls on the blocking thread pool, as SQLite
+// and Tantivy would otherwise hold up the async workers while they wait.
+pub async fn blocking<T, F>(f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    web::block(f).await.map_err(|e| Error::Internal(e.to_string()))?
+}
+
+// Every article ever scraped, one row per source and key.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+// Filters and paging for `Store::articles`.
+pub struct Query<'a> {
+    pub source: Option<&'a str>,
+    pub since: Option<String>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+// What one save did, by index into the articles saved. On a source's first
+// save everything is new, so `first` says not to make much of it.
+#[derive(Default)]
+pub struct Changes {
+    pub first: bool,
+    pub new: Vec<usize>,
+    pub changed: Vec<usize>,
+    // The row each article was saved to.
+    pub ids: Vec<i64>,
+}
+
+// Timestamps are stored in one fixed format so they sort as text.
+pub fn timestamp(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// Accepts an RFC 3339 time or a plain date.
+pub fn parse_since(s: &str) -> Option<String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(timestamp(t.with_timezone(&Utc)));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    Some(timestamp(date.and_hms_opt(0, 0, 0)?.and_utc()))
+}
+
+fn hash(json: &str) -> String {
+    hex::encode(Sha256::digest(json))
+}
+
+impl Store {
+    pub fn open(config: &StoreConfig) -> Result<Store, Error> {
+        let conn = Connection::open(&config.path).map_err(|e| Error::Store(format!("{}: {}", config.path, e)))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Store { conn: Mutex::new(conn) })
+    }
+
+    // Inserts new articles and refreshes the ones already known. Articles
+    // without a key are identified by their content instead.
+    pub fn save(&self, source: &str, key: &str, articles: &[Article]) -> Result<Changes, Error> {
+        let now = timestamp(Utc::now());
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut changes = Changes::default();
+        {
+            let known: bool = tx.query_row("SELECT EXISTS (SELECT 1 FROM articles WHERE source = ?1)", params![source], |row| row.get(0))?;
+            changes.first = !known;
+            let mut previous = tx.prepare("SELECT hash FROM articles WHERE source = ?1 AND key = ?2")?;
+            let mut upsert = tx.prepare(
+                "INSERT INTO articles (source, key, fields, hash, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+                 ON CONFLICT (source, key) DO UPDATE SET fields = excluded.fields, hash = excluded.hash, last_seen = excluded.last_seen
+                 RETURNING id",
+            )?;
+            for (i, article) in articles.iter().enumerate() {
+                let json = serde_json::to_string(article).map_err(|e| Error::Internal(e.to_string()))?;
+                let hash = hash(&json);
+                let key = match article.fields.get(key) {
+                    Some(Value::String(s)) => s.clone(),
+                    Some(Value::Null) | None => hash.clone(),
+                    Some(other) => other.to_string(),
+                };
+                match previous.query_row(params![source, key], |row| row.get::<_, String>(0)).optional()? {
+                    None => changes.new.push(i),
+                    Some(old) if old != hash => changes.changed.push(i),
+                    Some(_) => (),
+                }
+                changes.ids.push(upsert.query_row(params![source, key, json, hash, now], |row| row.get(0))?);
+            }
+        }
+        tx.commit()?;
+        Ok(changes)
+    }
+
+    // Newest first, with the total number of matches for paging.
+    pub fn articles(&self, query: &Query) -> Result<(Vec<StoredArticle>, usize), Error> {
+        let conn = self.conn.lock().unwrap();
+        let filter = "WHERE (?1 IS NULL OR source = ?1) AND (?2 IS NULL OR first_seen >= ?2)";
+        let total: usize = conn.query_row(&format!("SELECT COUNT(*) FROM articles {}", filter), params![query.source, query.since], |row| row.get(0))?;
+
+        let mut select = conn.prepare(&format!("SELECT {} FROM articles {} ORDER BY first_seen DESC, id DESC LIMIT ?3 OFFSET ?4", COLUMNS, filter))?;
+        let articles = select
+            .query_map(params![query.source, query.since, query.limit as i64, query.offset as i64], read)?
+            .collect::<Result<_, _>>()?;
+        Ok((articles, total))
+    }
+
+    // What the source's page held the last time it was scraped.
+    pub fn current(&self, source: &str) -> Result<Vec<StoredArticle>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare(&format!(
+            "SELECT {} FROM articles WHERE source = ?1 AND last_seen = (SELECT MAX(last_seen) FROM articles WHERE source = ?1) ORDER BY first_seen DESC, id DESC",
+            COLUMNS
+        ))?;
+        let articles = select.query_map(params![source], read)?.collect::<Result<_, _>>()?;
+        Ok(articles)
+    }
+
+    // The content hash of every row, by id.
+    pub fn hashes(&self) -> Result<HashMap<i64, String>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare("SELECT id, hash FROM articles")?;
+        let hashes = select.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+        Ok(hashes)
+    }
+
+    // The articles in these rows, in the same order, skipping any that are
+    // gone.
+    pub fn by_id(&self, ids: &[i64]) -> Result<Vec<StoredArticle>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare(&format!("SELECT {} FROM articles WHERE id = ?1", COLUMNS))?;
+        let mut articles = Vec::new();
+        for id in ids {
+            if let Some(article) = select.query_row(params![id], read).optional()? {
+                articles.push(article);
+            }
+        }
+        Ok(articles)
+    }
+}
+
+const COLUMNS: &str = "source, fields, hash, first_seen, last_seen, id";
+
+// A row whose fields no longer decode is an error rather than an empty article.
+fn read(row: &rusqlite::Row) -> rusqlite::Result<StoredArticle> {
+    let fields: String = row.get(1)?;
+    let article = serde_json::from_str::<Article>(&fields).map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e)))?;
+    Ok(StoredArticle {
+        source: row.get(0)?,
+        hash: row.get(2)?,
+        first_seen: row.get(3)?,
+        last_seen: row.get(4)?,
+        id: row.get(5)?,
+        article,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_rows_are_reported() {
+        let store = Store::open(&StoreConfig { path: ":memory:".to_string() }).unwrap();
+        let changes = store.save("news", "link", &[Article::default()]).unwrap();
+        store.conn.lock().unwrap().execute("UPDATE articles SET fields = '{' WHERE id = ?1", params![changes.ids[0]]).unwrap();
+
+        let all = Query { source: None, since: None, limit: 10, offset: 0 };
+        assert!(matches!(store.articles(&all), Err(Error::Store(_))));
+        assert!(store.by_id(&changes.ids).is_err());
+    }
+}
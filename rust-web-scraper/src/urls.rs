@@ -0,0 +1,81 @@
+use scraper::Html;
+use url::{form_urlencoded, Url};
+
+use crate::schema::parse_selector;
+
+// Query parameters that only track where a visitor came from.
+const TRACKING_PARAMS: [&str; 9] = ["fbclid", "gclid", "dclid", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid", "_ga"];
+
+fn is_tracking(name: &str) -> bool {
+    name.starts_with("utm_") || TRACKING_PARAMS.contains(&name)
+}
+
+// The URL relative links on a page are resolved against: its <base href> if
+// it has one, otherwise the page's own URL.
+pub fn base(document: &Html, page: &Url) -> Url {
+    let selector = parse_selector("base[href]").unwrap();
+    document
+        .select(&selector)
+        .next()
+        .and_then(|b| b.value().attr("href"))
+        .and_then(|href| page.join(href.trim()).ok())
+        .unwrap_or_else(|| page.clone())
+}
+
+// Lowercases the host, drops the fragment and tracking parameters, so the
+// same link always comes out the same way. The rest of the query is kept as
+// it was written.
+pub fn normalize(mut url: Url) -> Url {
+    if let Some(host) = url.host_str() {
+        let host = host.to_lowercase();
+        let _ = url.set_host(Some(&host));
+    }
+    url.set_fragment(None);
+    if let Some(query) = url.query() {
+        let params: Vec<&str> = query.split('&').collect();
+        let kept: Vec<&str> = params
+            .iter()
+            .copied()
+            .filter(|p| !form_urlencoded::parse(p.as_bytes()).next().map(|(k, _)| is_tracking(&k)).unwrap_or(false))
+            .collect();
+        if kept.len() != params.len() {
+            let kept = kept.join("&");
+            url.set_query(if kept.is_empty() { None } else { Some(&kept) });
+        }
+    }
+    url
+}
+
+pub fn resolve(base: &Url, href: &str) -> Option<Url> {
+    base.join(href.trim()).ok().map(normalize)
+}
+
+// The page's <link rel=canonical>, which names it better than the URL it was
+// reached through.
+pub fn canonical(document: &Html, page: &Url) -> Option<Url> {
+    let selector = parse_selector("link[rel~=canonical][href]").unwrap();
+    let href = document.select(&selector).next()?.value().attr("href")?;
+    resolve(page, href)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(url: &str) -> String {
+        normalize(Url::parse(url).unwrap()).to_string()
+    }
+
+    #[test]
+    fn tracking_parameters_are_dropped() {
+        assert_eq!(normalized("https://Example.COM/a?utm_source=x&id=1&fbclid=y#top"), "https://example.com/a?id=1");
+        assert_eq!(normalized("https://example.com/a?utm_source=x&utm_medium=y"), "https://example.com/a");
+    }
+
+    #[test]
+    fn the_rest_of_the_query_is_left_as_written() {
+        assert_eq!(normalized("https://example.com/a?print"), "https://example.com/a?print");
+        assert_eq!(normalized("https://example.com/a?q=caf%C3%A9+au+lait&x=a%2Fb"), "https://example.com/a?q=caf%C3%A9+au+lait&x=a%2Fb");
+        assert_eq!(normalized("https://example.com/a?print&utm_source=x&q=a%20b"), "https://example.com/a?print&q=a%20b");
+    }
+}